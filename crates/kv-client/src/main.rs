@@ -32,7 +32,7 @@ async fn main() {
         };
 
         let input = line;
-        let parts = input.trim().split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>();
+        let parts = input.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>();
         let Some(command) = parts.first() else {
             println!("ERROR: Invalid command");
             continue;
@@ -9,10 +9,10 @@ use uuid::Uuid;
 pub struct Proxy {
     /// A sorted array containing the IP addresses of the replicas in the system.
     pub configuration: Vec<String>,
-    /// The current view number. The primary replica is the one with the index `current_view` in the `configuration` array.
+    /// The current view number. The primary replica is the one with the index `current_view` modulo the size of the `configuration` array.
     pub current_view: usize,
-    /// The unique identifier of this client.
-    pub id: String,
+    /// The unique identifier of this client, allocated by the replica group when the proxy registers.
+    pub id: u64,
     /// The current request number. For future requests, it should ensure to be greater than the previous request number.
     pub request_number: u64,
     /// The current epoch number of the replica group.
@@ -22,17 +22,20 @@ pub struct Proxy {
 impl Proxy {
     pub async fn new(addr: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let data = connect_to_replica(addr).await?;
+        let primary_replica_addr = &data.configuration[data.current_view % data.configuration.len()];
+        let id = register(primary_replica_addr).await?;
         Ok(Self {
             configuration: data.configuration,
             current_view: data.current_view,
-            id: Uuid::new_v4().to_string(),
-            request_number: 0,
+            id,
+            // The session starts after the registration, which took request number 0.
+            request_number: 1,
             epoch: data.epoch,
         })
     }
 
     pub async fn send_write_request(&mut self, key: String, value: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let primary_replica_addr = self.configuration.get(self.current_view).unwrap();
+        let primary_replica_addr = self.configuration.get(self.current_view % self.configuration.len()).unwrap();
 
         let stream = TcpStream::connect(&primary_replica_addr).await?;
         let io = TokioIo::new(stream);
@@ -53,7 +56,7 @@ impl Proxy {
         #[derive(Debug, Serialize)]
         struct RequestData {
             r#type: String,
-            client_id: String,
+            client_id: u64,
             op: Vec<String>,
             request_number: u64,
         }
@@ -64,14 +67,14 @@ impl Proxy {
         .header(hyper::header::CONTENT_TYPE, "application/json")
         .body(Full::new(Bytes::from(serde_json::to_string(&RequestData {
             r#type: "request".to_string(),
-            client_id: self.id.clone(),
+            client_id: self.id,
             op,
             request_number,
         })?)))?;
 
         let Ok(res) = sender.send_request(req).await else {
             println!("Failed to send request");
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to send request")));
+            return Err(Box::new(std::io::Error::other("Failed to send request")));
         };
 
         let body = res.collect().await?.to_bytes();
@@ -101,7 +104,7 @@ async fn connect_to_replica(addr: &str) -> Result<ResponseClientData, Box<dyn st
         .body(Full::new(Bytes::from(r#"{"type": "connect"}"#)))?;
 
     let Ok(res) = sender.send_request(req).await else {
-        return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Failed to send request")));
+        return Err(Box::new(std::io::Error::other("Failed to send request")));
     };
 
     let body = res.collect().await?.aggregate();
@@ -109,6 +112,44 @@ async fn connect_to_replica(addr: &str) -> Result<ResponseClientData, Box<dyn st
     Ok(data)
 }
 
+/// Opens a session on the primary, which allocates the id of the client.
+async fn register(addr: &str) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = TcpStream::connect(addr).await?;
+    let io = TokioIo::new(stream);
+    let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, Full<Bytes>>(io).await?;
+
+    tokio::task::spawn(async move {
+        if let Err(err) = conn.await {
+            eprintln!("Connection failed: {:?}", err);
+        }
+    });
+
+    // The reply is routed by the nonce until the client has its id.
+    let nonce = Uuid::new_v4().as_u64_pair().0;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{}/register", addr))
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(format!(r#"{{"nonce": {}}}"#, nonce))))?;
+
+    let Ok(res) = sender.send_request(req).await else {
+        return Err(Box::new(std::io::Error::other("Failed to send request")));
+    };
+
+    let body = res.collect().await?.to_bytes();
+    match serde_json::from_slice(&body) {
+        Ok(RegisterResponse::Reply { client_id }) => Ok(client_id),
+        Err(_) => Err(Box::new(std::io::Error::other(String::from_utf8_lossy(&body).into_owned()))),
+    }
+}
+
+/// The reply of the replica to `/register`, of which only the allocated client id is read. Anything else, such as
+/// an error, fails the registration.
+#[derive(Debug, Deserialize)]
+enum RegisterResponse {
+    Reply { client_id: u64 },
+}
+
 #[derive(Debug, Deserialize)]
 struct ResponseClientData {
     configuration: Vec<String>,
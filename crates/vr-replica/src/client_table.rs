@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use crate::types::OpNumber;
+
+/// The latest committed request of a client, with the reply it got.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientTableEntry<O> {
+    pub request_number: usize,
+    pub result: Option<O>,
+    /// The op of the latest request, or of the `Register` that opened the session. The entry with the lowest one
+    /// is the least recently used.
+    pub op_number: OpNumber,
+}
+
+/// The latest committed request of every client, so that a retried request is answered from its cached reply
+/// instead of being executed again.
+///
+/// Every replica builds it from the ops it commits, in log order, so replicas that committed the same ops hold the
+/// same table. A checkpoint carries the table of the ops it covers, since their log entries are gone.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientTable<O> {
+    entries: BTreeMap<u64, ClientTableEntry<O>>,
+    /// The client id of every open session, by the nonce of the `Register` that opened it.
+    registrations: BTreeMap<u64, u64>,
+}
+
+impl<O> Default for ClientTable<O> {
+    fn default() -> Self {
+        Self { entries: BTreeMap::new(), registrations: BTreeMap::new() }
+    }
+}
+
+impl<O> ClientTable<O> {
+    pub fn get(&self, client_id: u64) -> Option<&ClientTableEntry<O>> {
+        self.entries.get(&client_id)
+    }
+
+    /// Records the reply to a committed request. Requests are committed in order, so it replaces the previous one.
+    pub fn record(&mut self, client_id: u64, request_number: usize, result: Option<O>, op_number: OpNumber) {
+        self.entries.insert(client_id, ClientTableEntry { request_number, result, op_number });
+    }
+
+    /// The client id of the session the `Register` with `nonce` opened, while it is open.
+    pub fn client_of(&self, nonce: u64) -> Option<u64> {
+        self.registrations.get(&nonce).copied()
+    }
+
+    /// Opens the session of a `Register` with `nonce` committed at `op_number`, which is the id of the new client,
+    /// and returns that id. A `Register` whose nonce already opened a session gets the same id back instead. Once the
+    /// table holds `max_clients`, the least recently used client is evicted first.
+    ///
+    /// Without `max_clients` sessions are not required, so the nonce is not kept: nothing would ever evict it.
+    pub fn register(&mut self, nonce: u64, op_number: OpNumber, max_clients: Option<usize>) -> u64 {
+        if let Some(client_id) = self.client_of(nonce) {
+            return client_id;
+        }
+
+        if max_clients.is_some_and(|max_clients| self.entries.len() >= max_clients) {
+            self.evict_least_recently_used();
+        }
+
+        let client_id = op_number as u64;
+        self.record(client_id, 0, None, op_number);
+        if max_clients.is_some() {
+            self.registrations.insert(nonce, client_id);
+        }
+        client_id
+    }
+
+    /// The entries, by client id.
+    pub fn iter(&self) -> impl Iterator<Item = (&u64, &ClientTableEntry<O>)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.op_number).map(|(client_id, _)| *client_id);
+        if let Some(client_id) = oldest {
+            self.entries.remove(&client_id);
+            self.registrations.retain(|_, registered| *registered != client_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_evicts_the_least_recently_used_client() {
+        let mut table = ClientTable::<()>::default();
+        assert_eq!(table.register(10, 1, Some(2)), 1);
+        assert_eq!(table.register(20, 2, Some(2)), 2);
+
+        // Client 1 sends a request after client 2 registered, so client 2 is evicted.
+        table.record(1, 1, Some(()), 3);
+        assert_eq!(table.register(30, 4, Some(2)), 4);
+        assert_eq!(table.iter().map(|(client_id, _)| *client_id).collect::<Vec<_>>(), vec![1, 4]);
+        assert_eq!(table.client_of(20), None);
+
+        // Without a bound nothing is evicted.
+        assert_eq!(table.register(40, 5, None), 5);
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn test_register_with_a_known_nonce_returns_the_open_session() {
+        let mut table = ClientTable::<()>::default();
+        assert_eq!(table.register(10, 1, Some(1)), 1);
+
+        // A retried `Register` neither opens a second session nor evicts the first one.
+        assert_eq!(table.register(10, 2, Some(1)), 1);
+        assert_eq!(table.iter().map(|(client_id, _)| *client_id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn test_register_without_a_bound_keeps_no_nonces() {
+        let mut table = ClientTable::<()>::default();
+        for nonce in 0..1000 {
+            table.register(nonce, nonce as OpNumber + 1, None);
+        }
+
+        assert_eq!(table.len(), 1000);
+        assert!(table.registrations.is_empty());
+    }
+}
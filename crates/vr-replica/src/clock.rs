@@ -1,5 +1,10 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TimerKind {
     BackupWatchdog,
     PrimaryIdleCommit,
+    /// Syncs the log entries that the `FsyncPolicy` left pending.
+    LogSync,
+    /// Prepares the requests the primary batched so far.
+    BatchFlush,
 }
@@ -0,0 +1,168 @@
+//! Length-prefixed framing for sending messages over a byte stream.
+//!
+//! Each frame is a version byte, the payload length as a big-endian `u32`, and the JSON encoded payload.
+//! `FrameCodec` implements `tokio_util::codec::{Encoder, Decoder}`, so it works with `Framed` on any tokio transport.
+
+use std::marker::PhantomData;
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The version of the frame format. It is also the first byte of every connection that uses this codec.
+pub const VERSION: u8 = 1;
+
+/// Frames larger than this are rejected, so a corrupted length cannot make the decoder allocate without bound.
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+const HEADER_LENGTH: usize = 5;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    UnsupportedVersion(u8),
+    FrameTooLarge(usize),
+    Payload(serde_json::Error),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Io(err) => write!(f, "io error: {}", err),
+            CodecError::UnsupportedVersion(version) => write!(f, "unsupported frame version: {}", version),
+            CodecError::FrameTooLarge(length) => write!(f, "frame of {} bytes exceeds {} bytes", length, MAX_FRAME_LENGTH),
+            CodecError::Payload(err) => write!(f, "invalid payload: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(err: std::io::Error) -> Self {
+        CodecError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Payload(err)
+    }
+}
+
+/// Encodes and decodes frames carrying a `T`, usually a `Message` or a `ClientRequest`.
+#[derive(Debug)]
+pub struct FrameCodec<T> {
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> FrameCodec<T> {
+    pub fn new() -> Self {
+        Self { _item: PhantomData }
+    }
+}
+
+impl<T> Default for FrameCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Serialize> Encoder<T> for FrameCodec<T> {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let payload = serde_json::to_vec(&item)?;
+        if payload.len() > MAX_FRAME_LENGTH {
+            return Err(CodecError::FrameTooLarge(payload.len()));
+        }
+
+        dst.reserve(HEADER_LENGTH + payload.len());
+        dst.put_u8(VERSION);
+        dst.put_u32(payload.len() as u32);
+        dst.extend_from_slice(&payload);
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for FrameCodec<T> {
+    type Item = T;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LENGTH {
+            return Ok(None);
+        }
+
+        if src[0] != VERSION {
+            return Err(CodecError::UnsupportedVersion(src[0]));
+        }
+
+        let length = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+        if length > MAX_FRAME_LENGTH {
+            return Err(CodecError::FrameTooLarge(length));
+        }
+
+        if src.len() < HEADER_LENGTH + length {
+            src.reserve(HEADER_LENGTH + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_LENGTH);
+        let payload = src.split_to(length);
+        Ok(Some(serde_json::from_slice(&payload)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ReplicaError;
+    use crate::message::{ClientRequest, Message, Operation};
+
+    type TestMessage = Message<String, String>;
+
+    #[test]
+    fn test_round_trip_message() {
+        let mut codec = FrameCodec::<TestMessage>::new();
+        let mut buf = BytesMut::new();
+        let request = ClientRequest {
+            op: Operation::Apply("SET a 1".to_string()),
+            client_id: 7,
+            request_number: 3,
+            result: None,
+        };
+
+        codec.encode(Message::Request(request), &mut buf).unwrap();
+        codec.encode(Message::Commit { op_number: 1, commit_number: 1, epoch: 0, view_number: 2 }, &mut buf).unwrap();
+        assert_eq!(buf[0], VERSION);
+
+        let Some(Message::Request(request)) = codec.decode(&mut buf).unwrap() else {
+            panic!("expected a request");
+        };
+        assert_eq!(request.client_id, 7);
+        assert_eq!(request.request_number, 3);
+        assert!(matches!(codec.decode(&mut buf).unwrap(), Some(Message::Commit { view_number: 2, .. })));
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decode_waits_for_a_full_frame() {
+        let mut codec = FrameCodec::<TestMessage>::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Error { error: ReplicaError::ReconfigurationPending }, &mut buf).unwrap();
+
+        let mut partial = buf.split_to(buf.len() - 1);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        assert!(matches!(codec.decode(&mut partial).unwrap(), Some(Message::Error { .. })));
+    }
+
+    #[test]
+    fn test_decode_rejects_unknown_version() {
+        let mut codec = FrameCodec::<TestMessage>::new();
+        let mut buf = BytesMut::from(&[VERSION + 1, 0, 0, 0, 0][..]);
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::UnsupportedVersion(_))));
+    }
+}
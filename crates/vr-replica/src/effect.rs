@@ -2,6 +2,8 @@ use crate::clock::TimerKind;
 use crate::message::Message;
 use crate::types::{OpNumber, ReplicaId};
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Effect<I, O> {
     Send { to: ReplicaId,  message: Message<I, O> },
     Broadcast { to: Vec<ReplicaId>, message: Message<I, O> },
@@ -9,6 +11,11 @@ pub enum Effect<I, O> {
     CancelTimer { kind: TimerKind },
     ApplyCommited { op_number: OpNumber },
     Reply { client_id: u64, message: Message<I, O> },
+    /// The replica left the configuration and is no longer needed by the new epoch.
+    Shutdown,
+    /// The storage of the replica failed, or it could not restore a checkpoint, so it stopped. It may restart from
+    /// what the storage holds.
+    Failed { error: String },
 }
 
 impl<I, O> std::fmt::Debug for Effect<I, O>
@@ -24,6 +31,8 @@ where
             Effect::CancelTimer { kind } => write!(f, "CancelTimer {{ kind: {:?} }}", kind),
             Effect::ApplyCommited { op_number } => write!(f, "ApplyCommited {{ op_number: {:?} }}", op_number),
             Effect::Reply { client_id, message } => write!(f, "Reply {{ client_id: {:?}, message: {:?} }}", client_id, message),
+            Effect::Shutdown => write!(f, "Shutdown"),
+            Effect::Failed { error } => write!(f, "Failed {{ error: {:?} }}", error),
         }
     }
 }
@@ -0,0 +1,47 @@
+use crate::replica::Status;
+use crate::types::ReplicaId;
+
+/// Why a replica turned down a client request, as sent back in a `Message::Error`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReplicaError {
+    /// The request went to a backup. The primary of `view_number` takes it.
+    NotPrimary { view_number: ReplicaId, primary: ReplicaId },
+    /// The replica is not in the normal status of its view, e.g. during a view change, so the client should retry.
+    WrongView { view_number: ReplicaId, status: Status },
+    /// The client already sent a newer request, which the older one must not overtake.
+    StaleRequest { client_id: u64, request_number: usize, last_request_number: usize },
+    /// The client never registered, or its session was evicted. It has to register again.
+    UnknownSession { client_id: u64 },
+    /// A reconfiguration names an epoch other than the current one.
+    WrongEpoch { epoch: u64 },
+    /// A reconfiguration leaves no replica.
+    EmptyConfiguration,
+    /// No request is taken after a reconfiguration until the new epoch starts.
+    ReconfigurationPending,
+    /// A read whose operation may change the state machine, which must go through the log instead.
+    NotReadOnly,
+}
+
+impl std::fmt::Display for ReplicaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaError::NotPrimary { view_number, primary } => {
+                write!(f, "not the primary, replica {} is the primary of view {}", primary, view_number)
+            }
+            ReplicaError::WrongView { view_number, status } => write!(f, "view {} is not normal: {:?}", view_number, status),
+            ReplicaError::StaleRequest { client_id, request_number, last_request_number } => write!(
+                f,
+                "request {} of client {} is older than its request {}",
+                request_number, client_id, last_request_number
+            ),
+            ReplicaError::UnknownSession { client_id } => write!(f, "unknown session: {}", client_id),
+            ReplicaError::WrongEpoch { epoch } => write!(f, "the current epoch is {}", epoch),
+            ReplicaError::EmptyConfiguration => write!(f, "the configuration is empty"),
+            ReplicaError::ReconfigurationPending => write!(f, "a reconfiguration is pending"),
+            ReplicaError::NotReadOnly => write!(f, "not a read-only operation"),
+        }
+    }
+}
+
+impl std::error::Error for ReplicaError {}
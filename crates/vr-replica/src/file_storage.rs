@@ -0,0 +1,479 @@
+//! A `LogStorage` that appends to segment files in a directory.
+//!
+//! Every change is appended as a record: a log entry, a truncation, or the replica metadata. A record is its
+//! payload length as a big-endian `u32`, the CRC-32 of the payload as a big-endian `u32`, and the JSON encoded
+//! payload. Replaying the records in order rebuilds the log. A record cut short or failing its checksum at the end
+//! of the last segment is what a crash during a write leaves behind, so it is discarded when the storage opens.
+//!
+//! The latest checkpoint is kept in its own file, replaced atomically: its op number as a big-endian `u64`, the
+//! CRC-32 of the rest as a big-endian `u32`, the length of the JSON encoded client table as a big-endian `u32`, the
+//! client table, and the snapshot. Saving one rewrites the entries after it to a new segment, and deletes the older
+//! segments.
+//!
+//! The fsync policy only covers log entries. Metadata, and the segment a checkpoint rewrites the log into, are
+//! always synced.
+
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::message::{Checkpoint, ClientRequest, Log};
+use crate::storage::{self, DurableState, FsyncPolicy, LogStorage, Metadata, SyncState};
+use crate::types::OpNumber;
+
+/// Segments are closed and a new one started once they grow past this size.
+pub const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+const HEADER_LENGTH: usize = 8;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+#[derive(Serialize, Deserialize)]
+enum Record<Input, Output> {
+    Entry(OpNumber, ClientRequest<Input, Output>),
+    Truncate(OpNumber),
+    Metadata(Metadata),
+}
+
+#[derive(Debug)]
+pub struct FileLogStorage<Input, Output> {
+    dir: PathBuf,
+    segment: File,
+    segment_index: u64,
+    segment_bytes: u64,
+    max_segment_bytes: u64,
+    op_number: OpNumber,
+    durable_op_number: OpNumber,
+    sync_state: SyncState,
+    _entry: PhantomData<fn() -> (Input, Output)>,
+}
+
+impl<Input, Output> FileLogStorage<Input, Output>
+where
+    Input: Serialize + DeserializeOwned,
+    Output: Serialize + DeserializeOwned,
+{
+    /// Opens the storage in `dir`, creating the directory if needed.
+    pub fn open(dir: impl AsRef<Path>, policy: FsyncPolicy) -> std::io::Result<Self> {
+        Self::with_max_segment_bytes(dir, policy, MAX_SEGMENT_BYTES)
+    }
+
+    pub fn with_max_segment_bytes(dir: impl AsRef<Path>, policy: FsyncPolicy, max_segment_bytes: u64) -> std::io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let (state, torn_at) = replay::<Input, Output>(&dir)?;
+        let op_number = storage::last_op_number(&state.log, state.checkpoint.as_ref());
+        let segment_index = segment_indexes(&dir)?.last().copied().unwrap_or(0);
+        // New records must follow the last whole one, so a torn write is cut off.
+        if let Some(length) = torn_at {
+            OpenOptions::new().write(true).open(segment_path(&dir, segment_index))?.set_len(length)?;
+        }
+        let segment = OpenOptions::new().create(true).append(true).open(segment_path(&dir, segment_index))?;
+        let segment_bytes = segment.metadata()?.len();
+
+        Ok(Self {
+            dir,
+            segment,
+            segment_index,
+            segment_bytes,
+            max_segment_bytes,
+            op_number,
+            durable_op_number: op_number,
+            sync_state: SyncState::new(policy),
+            _entry: PhantomData,
+        })
+    }
+
+    fn write(&mut self, record: &Record<Input, Output>) -> std::io::Result<()> {
+        if self.segment_bytes >= self.max_segment_bytes {
+            self.roll_segment()?;
+        }
+
+        let payload = serde_json::to_vec(record)?;
+        let mut buf = Vec::with_capacity(HEADER_LENGTH + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&crc32(&payload).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        self.segment.write_all(&buf)?;
+        self.segment_bytes += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Closes the current segment and starts the next one.
+    fn roll_segment(&mut self) -> std::io::Result<()> {
+        self.segment.sync_data()?;
+        self.segment_index += 1;
+        self.segment = OpenOptions::new().create(true).append(true).open(segment_path(&self.dir, self.segment_index))?;
+        self.segment_bytes = 0;
+        // The new file only survives a crash once the directory entry pointing to it is durable.
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Syncs the current segment whatever the policy, which makes everything written so far durable.
+    fn sync_segment(&mut self) -> std::io::Result<()> {
+        self.segment.sync_data()?;
+        self.sync_state.pending = 0;
+        self.durable_op_number = self.op_number;
+        Ok(())
+    }
+
+    /// Writes the checkpoint to a temporary file, then renames it over the previous one.
+    fn write_checkpoint(&self, checkpoint: &Checkpoint<Output>) -> std::io::Result<()> {
+        let client_table = serde_json::to_vec(&checkpoint.client_table)?;
+        let mut body = Vec::with_capacity(4 + client_table.len() + checkpoint.data.len());
+        body.extend_from_slice(&(client_table.len() as u32).to_be_bytes());
+        body.extend_from_slice(&client_table);
+        body.extend_from_slice(&checkpoint.data);
+
+        let mut buf = Vec::with_capacity(12 + body.len());
+        buf.extend_from_slice(&(checkpoint.op_number as u64).to_be_bytes());
+        buf.extend_from_slice(&crc32(&body).to_be_bytes());
+        buf.extend_from_slice(&body);
+
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        std::fs::rename(&tmp, self.dir.join(CHECKPOINT_FILE))?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl<Input, Output> LogStorage<Input, Output> for FileLogStorage<Input, Output>
+where
+    Input: Clone + Debug + Serialize + DeserializeOwned,
+    Output: Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn append(&mut self, op_number: OpNumber, request: &ClientRequest<Input, Output>) -> std::io::Result<()> {
+        self.write(&Record::Entry(op_number, request.clone()))?;
+        self.op_number = op_number;
+        if self.sync_state.on_append() {
+            self.sync()?;
+        }
+        if self.sync_state.policy == FsyncPolicy::None {
+            self.durable_op_number = op_number;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, op_number: OpNumber) -> std::io::Result<()> {
+        if op_number >= self.op_number {
+            return Ok(());
+        }
+
+        self.write(&Record::Truncate(op_number))?;
+        self.op_number = op_number;
+        self.durable_op_number = self.durable_op_number.min(op_number);
+        Ok(())
+    }
+
+    fn save_metadata(&mut self, metadata: &Metadata) -> std::io::Result<()> {
+        self.write(&Record::Metadata(metadata.clone()))?;
+        self.sync_segment()
+    }
+
+    fn save_checkpoint(&mut self, checkpoint: &Checkpoint<Output>) -> std::io::Result<()> {
+        self.write_checkpoint(checkpoint)?;
+
+        // Segments written before the checkpoint may still hold entries after it, so those are copied over first.
+        let (state, _) = replay::<Input, Output>(&self.dir)?;
+        self.roll_segment()?;
+        let first_segment = self.segment_index;
+        if let Some(metadata) = state.metadata {
+            self.write(&Record::Metadata(metadata))?;
+        }
+        for (op_number, request) in state.log {
+            self.write(&Record::Entry(op_number, request))?;
+        }
+
+        // The older segments are the only copy of these entries until the new segment and its directory entry are
+        // durable, whatever the policy.
+        self.sync_segment()?;
+        File::open(&self.dir)?.sync_all()?;
+
+        for index in segment_indexes(&self.dir)? {
+            if index < first_segment {
+                std::fs::remove_file(segment_path(&self.dir, index))?;
+            }
+        }
+
+        self.op_number = self.op_number.max(checkpoint.op_number);
+        self.durable_op_number = self.op_number;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.sync_state.policy != FsyncPolicy::None {
+            return self.sync_segment();
+        }
+        self.sync_state.pending = 0;
+        self.durable_op_number = self.op_number;
+        Ok(())
+    }
+
+    fn durable_op_number(&self) -> OpNumber {
+        self.durable_op_number
+    }
+
+    fn sync_delay(&self) -> Option<u64> {
+        self.sync_state.sync_delay()
+    }
+
+    fn load(&mut self) -> std::io::Result<DurableState<Input, Output>> {
+        replay(&self.dir).map(|(state, _)| state)
+    }
+}
+
+/// Rebuilds the metadata and the log from every segment in `dir`, leaving out a torn write at the end. Returns the
+/// length of the last segment without it too, if there is one.
+fn replay<Input, Output>(dir: &Path) -> std::io::Result<(DurableState<Input, Output>, Option<u64>)>
+where
+    Input: DeserializeOwned,
+    Output: DeserializeOwned,
+{
+    let mut metadata = None;
+    let mut log: Log<Input, Output> = Vec::new();
+    let checkpoint = read_checkpoint(dir)?;
+    let indexes = segment_indexes(dir)?;
+    let mut torn_at = None;
+
+    for (i, index) in indexes.iter().enumerate() {
+        let path = segment_path(dir, *index);
+        let mut bytes = Vec::new();
+        File::open(&path)?.read_to_end(&mut bytes)?;
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let Some(payload) = read_record(&bytes[offset..]) else {
+                if i + 1 < indexes.len() {
+                    return Err(std::io::Error::new(ErrorKind::InvalidData, format!("corrupted record in {:?}", path)));
+                }
+
+                torn_at = Some(offset as u64);
+                break;
+            };
+            offset += HEADER_LENGTH + payload.len();
+
+            match serde_json::from_slice(payload)? {
+                Record::Entry(op_number, request) => {
+                    storage::truncate_log(&mut log, op_number - 1);
+                    log.push((op_number, request));
+                }
+                Record::Truncate(op_number) => storage::truncate_log(&mut log, op_number),
+                Record::Metadata(m) => metadata = Some(m),
+            }
+        }
+    }
+
+    if let Some(checkpoint) = &checkpoint {
+        log.retain(|(op_number, _)| *op_number > checkpoint.op_number);
+    }
+
+    Ok((DurableState { metadata, checkpoint, log }, torn_at))
+}
+
+fn read_checkpoint<Output: DeserializeOwned>(dir: &Path) -> std::io::Result<Option<Checkpoint<Output>>> {
+    let bytes = match std::fs::read(dir.join(CHECKPOINT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let invalid = || std::io::Error::new(ErrorKind::InvalidData, "corrupted checkpoint");
+    let header = bytes.get(..12).ok_or_else(invalid)?;
+    let op_number = u64::from_be_bytes(header[..8].try_into().unwrap()) as OpNumber;
+    let checksum = u32::from_be_bytes(header[8..].try_into().unwrap());
+    let body = &bytes[12..];
+    if crc32(body) != checksum {
+        return Err(invalid());
+    }
+
+    let length = u32::from_be_bytes(body.get(..4).ok_or_else(invalid)?.try_into().unwrap()) as usize;
+    let client_table = body.get(4..4 + length).ok_or_else(invalid)?;
+    let client_table = serde_json::from_slice(client_table)?;
+    let data = body[4 + length..].to_vec();
+
+    Ok(Some(Checkpoint { op_number, data, client_table }))
+}
+
+/// Returns the payload of the record at the start of `bytes`, unless it is incomplete or fails its checksum.
+fn read_record(bytes: &[u8]) -> Option<&[u8]> {
+    let header = bytes.get(..HEADER_LENGTH)?;
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let checksum = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let payload = bytes.get(HEADER_LENGTH..HEADER_LENGTH + length)?;
+    (crc32(payload) == checksum).then_some(payload)
+}
+
+fn segment_indexes(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut indexes = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let index = name.to_str()
+            .and_then(|name| name.strip_prefix("segment-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|index| index.parse::<u64>().ok());
+        indexes.extend(index);
+    }
+    indexes.sort();
+    Ok(indexes)
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("segment-{:08}.log", index))
+}
+
+/// CRC-32 (IEEE), computed bit by bit. Records are small, so a lookup table is not worth it.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_table::ClientTable;
+    use crate::message::Operation;
+
+    type TestStorage = FileLogStorage<String, String>;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vr-replica-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn request(op: &str) -> ClientRequest<String, String> {
+        ClientRequest { op: Operation::Apply(op.to_string()), client_id: 1, request_number: 1, result: None }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_reopen_replays_the_log() {
+        let dir = test_dir("reopen");
+        let metadata = Metadata { epoch: 0, view_number: 2, last_normal_view: 2, configuration: vec![0, 1, 2] };
+        {
+            // Small segments, so that the log spans several files.
+            let mut storage = TestStorage::with_max_segment_bytes(&dir, FsyncPolicy::PerOp, 64).unwrap();
+            for (i, op) in ["SET a 1", "SET b 2", "SET c 3"].iter().enumerate() {
+                storage.append(i + 1, &request(op)).unwrap();
+            }
+            storage.truncate(1).unwrap();
+            storage.append(2, &request("SET d 4")).unwrap();
+            storage.save_metadata(&metadata).unwrap();
+            storage.sync().unwrap();
+            assert_eq!(storage.durable_op_number(), 2);
+        }
+
+        assert!(segment_indexes(&dir).unwrap().len() > 1);
+        let mut storage = TestStorage::open(&dir, FsyncPolicy::PerOp).unwrap();
+        let DurableState { metadata: loaded, log, .. } = storage.load().unwrap();
+        assert_eq!(loaded, Some(metadata));
+        let ops = log.iter()
+            .map(|(n, r)| match &r.op {
+                Operation::Apply(op) => (*n, op.as_str()),
+                op => panic!("unexpected operation: {:?}", op),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec![(1, "SET a 1"), (2, "SET d 4")]);
+        assert_eq!(storage.durable_op_number(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_discards_a_torn_record() {
+        let dir = test_dir("torn");
+        {
+            let mut storage = TestStorage::open(&dir, FsyncPolicy::PerOp).unwrap();
+            storage.append(1, &request("SET a 1")).unwrap();
+            storage.append(2, &request("SET b 2")).unwrap();
+        }
+
+        let path = segment_path(&dir, 0);
+        let length = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+
+        // Reading the segments back leaves them as they are, only opening the storage cuts the torn record off.
+        let (state, torn_at) = replay::<String, String>(&dir).unwrap();
+        assert_eq!((state.log.len(), std::fs::metadata(&path).unwrap().len()), (1, length - 3));
+
+        let mut storage = TestStorage::open(&dir, FsyncPolicy::PerOp).unwrap();
+        assert_eq!(Some(std::fs::metadata(&path).unwrap().len()), torn_at);
+        assert_eq!(storage.load().unwrap().log.len(), 1);
+        storage.append(2, &request("SET c 3")).unwrap();
+        assert_eq!(storage.load().unwrap().log.len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_drops_older_segments() {
+        let dir = test_dir("checkpoint");
+        let metadata = Metadata { epoch: 0, view_number: 1, last_normal_view: 1, configuration: vec![0, 1, 2] };
+        let mut client_table = ClientTable::default();
+        client_table.record(7, 3, Some("OK".to_string()), 3);
+        let checkpoint = Checkpoint { op_number: 3, data: b"a=1".to_vec(), client_table };
+        {
+            let mut storage = TestStorage::with_max_segment_bytes(&dir, FsyncPolicy::PerOp, 64).unwrap();
+            storage.save_metadata(&metadata).unwrap();
+            for i in 1..=5 {
+                storage.append(i, &request("SET a 1")).unwrap();
+            }
+            storage.save_checkpoint(&checkpoint).unwrap();
+            storage.append(6, &request("SET b 2")).unwrap();
+        }
+
+        assert!(!segment_path(&dir, 0).exists());
+        let mut storage = TestStorage::open(&dir, FsyncPolicy::PerOp).unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(state.metadata, Some(metadata));
+        assert_eq!(state.checkpoint, Some(checkpoint));
+        assert_eq!(state.log.iter().map(|(n, _)| *n).collect::<Vec<_>>(), vec![4, 5, 6]);
+        assert_eq!(storage.durable_op_number(), 6);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_batched_policy_waits_for_a_sync() {
+        let dir = test_dir("batched");
+        let mut storage = TestStorage::open(&dir, FsyncPolicy::Batched { max_ops: 2, max_delay_ms: 10 }).unwrap();
+        storage.append(1, &request("SET a 1")).unwrap();
+        assert_eq!(storage.durable_op_number(), 0);
+        storage.append(2, &request("SET b 2")).unwrap();
+        assert_eq!(storage.durable_op_number(), 2);
+        storage.append(3, &request("SET c 3")).unwrap();
+        assert_eq!(storage.durable_op_number(), 2);
+        storage.sync().unwrap();
+        assert_eq!(storage.durable_op_number(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metadata_is_synced_whatever_the_policy() {
+        let dir = test_dir("metadata");
+        let metadata = Metadata { epoch: 0, view_number: 2, last_normal_view: 1, configuration: vec![0, 1, 2] };
+        let mut storage = TestStorage::open(&dir, FsyncPolicy::Batched { max_ops: 10, max_delay_ms: 10 }).unwrap();
+        storage.append(1, &request("SET a 1")).unwrap();
+        assert_eq!(storage.durable_op_number(), 0);
+
+        // The entry before the metadata is synced along with it.
+        storage.save_metadata(&metadata).unwrap();
+        assert_eq!(storage.durable_op_number(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,15 @@
+pub mod client_table;
+pub mod error;
 pub mod message;
+pub mod quorum;
 pub mod replica;
 pub mod state_machine;
 pub mod effect;
 pub mod message_bus;
 pub mod types;
 pub mod clock;
+pub mod storage;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "file-storage")]
+pub mod file_storage;
@@ -1,38 +1,87 @@
-use crate::types::ReplicaId;
+use crate::client_table::ClientTable;
+use crate::error::ReplicaError;
+use crate::types::{OpNumber, ReplicaId};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Operation<I> {
+    /// An operation on the application `StateMachine`.
+    Apply(I),
+    /// Moves the replica group from `epoch` to the next epoch with a new configuration.
+    Reconfigure {
+        epoch: u64,
+        configuration: Vec<ReplicaId>,
+    },
+    /// Opens a client session. Its op number becomes the id of the client, which the reply carries.
+    ///
+    /// The `client_id` of the request is a nonce, e.g. a random number, that routes the reply back. A retried
+    /// `Register` with the same nonce gets the client id of the session the first one opened.
+    Register,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ClientRequest<I, O> {
-    pub op: I,
+    pub op: Operation<I>,
     pub client_id: u64,
     pub request_number: usize,
     pub result: Option<O>,
 }
 
+/// A request that only reads the state machine. The primary answers it without appending it to the log.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReadRequest<I> {
+    pub op: I,
+    pub client_id: u64,
+    pub request_number: usize,
+}
+
+/// The replicated log, as shipped between replicas during view change, recovery and state transfer.
+pub type Log<I, O> = Vec<(OpNumber, ClientRequest<I, O>)>;
+
+/// A `Snapshot` of the state machine after executing every op up to `op_number`, with the client table of those
+/// ops. A replica keeps its latest checkpoint, and only the log entries after it.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint<O> {
+  pub op_number: OpNumber,
+  pub data: Vec<u8>,
+  pub client_table: ClientTable<O>,
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message<I, O> {
+  /// Turns down a client request.
   Error {
-    message: String,
+    error: ReplicaError,
   },
   Request(ClientRequest<I, O>),
+  Read(ReadRequest<I>),
   Connect {
     configuration: Vec<String>,
     current_view: usize,
     epoch: usize,
   },
   Reply {
+    /// The client the reply is for, or the id allocated to the client by a `Register`.
     client_id: u64,
     view_number: ReplicaId,
     request_id: usize,
     result: Option<O>,
   },
   Prepare {
-    op: I,
+    epoch: u64,
     view_number: ReplicaId,
+    /// The op number of the last request in the batch.
     op_number: usize,
     commit_number: usize,
-    request: Box<ClientRequest<I, O>>,
+    /// The requests of the ops `op_number - requests.len() + 1..=op_number`, in order.
+    requests: Vec<ClientRequest<I, O>>,
   },
   PrepareOk {
+    epoch: u64,
     view_number: ReplicaId,
     replica_number: ReplicaId,
     op_number: usize,
@@ -41,6 +90,131 @@ pub enum Message<I, O> {
   Commit {
     op_number: usize,
     commit_number: usize,
+    epoch: u64,
     view_number: ReplicaId,
-  }
+  },
+  /// Asks the backups to confirm that the sender is still their primary, before it answers reads.
+  Heartbeat {
+    epoch: u64,
+    view_number: ReplicaId,
+    commit_number: usize,
+    read_number: u64,
+  },
+  HeartbeatOk {
+    epoch: u64,
+    view_number: ReplicaId,
+    read_number: u64,
+    replica_number: ReplicaId,
+  },
+  StartViewChange {
+    epoch: u64,
+    view_number: ReplicaId,
+    replica_number: ReplicaId,
+  },
+  DoViewChange {
+    epoch: u64,
+    view_number: ReplicaId,
+    log: Log<I, O>,
+    /// The checkpoint the log follows, if the sender compacted its log.
+    checkpoint: Option<Checkpoint<O>>,
+    /// The latest view in which the sender had `Status::Normal`.
+    last_normal_view: ReplicaId,
+    op_number: usize,
+    commit_number: usize,
+    replica_number: ReplicaId,
+  },
+  StartView {
+    epoch: u64,
+    view_number: ReplicaId,
+    log: Log<I, O>,
+    checkpoint: Option<Checkpoint<O>>,
+    op_number: usize,
+    commit_number: usize,
+  },
+  GetState {
+    epoch: u64,
+    view_number: ReplicaId,
+    op_number: usize,
+    replica_number: ReplicaId,
+  },
+  NewState {
+    epoch: u64,
+    view_number: ReplicaId,
+    /// The log entries after the `op_number` of the matching `GetState`.
+    log: Log<I, O>,
+    /// Set when the entries the `GetState` asked for were compacted away.
+    checkpoint: Option<Checkpoint<O>>,
+    op_number: usize,
+    commit_number: usize,
+  },
+  StartEpoch {
+    epoch: u64,
+    /// The op number of the reconfiguration that started `epoch`.
+    op_number: usize,
+    old_configuration: Vec<ReplicaId>,
+    configuration: Vec<ReplicaId>,
+    replica_number: ReplicaId,
+  },
+  EpochStarted {
+    epoch: u64,
+    replica_number: ReplicaId,
+  },
+  Recovery {
+    replica_number: ReplicaId,
+    nonce: u64,
+  },
+  RecoveryResponse {
+    view_number: ReplicaId,
+    nonce: u64,
+    /// Only the primary sends its log, op number and commit number.
+    log: Option<Log<I, O>>,
+    checkpoint: Option<Checkpoint<O>>,
+    op_number: Option<usize>,
+    commit_number: Option<usize>,
+    replica_number: ReplicaId,
+  },
+}
+
+impl<I, O> Message<I, O> {
+    /// The name of the variant, e.g. to count messages by kind.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Error { .. } => "Error",
+            Message::Request(_) => "Request",
+            Message::Read(_) => "Read",
+            Message::Connect { .. } => "Connect",
+            Message::Reply { .. } => "Reply",
+            Message::Prepare { .. } => "Prepare",
+            Message::PrepareOk { .. } => "PrepareOk",
+            Message::Commit { .. } => "Commit",
+            Message::Heartbeat { .. } => "Heartbeat",
+            Message::HeartbeatOk { .. } => "HeartbeatOk",
+            Message::StartViewChange { .. } => "StartViewChange",
+            Message::DoViewChange { .. } => "DoViewChange",
+            Message::StartView { .. } => "StartView",
+            Message::GetState { .. } => "GetState",
+            Message::NewState { .. } => "NewState",
+            Message::StartEpoch { .. } => "StartEpoch",
+            Message::EpochStarted { .. } => "EpochStarted",
+            Message::Recovery { .. } => "Recovery",
+            Message::RecoveryResponse { .. } => "RecoveryResponse",
+        }
+    }
+
+    /// The epoch of the messages exchanged within a replica group, which are only meaningful in that epoch.
+    pub fn epoch(&self) -> Option<u64> {
+        match self {
+            Message::Prepare { epoch, .. }
+            | Message::PrepareOk { epoch, .. }
+            | Message::Commit { epoch, .. }
+            | Message::Heartbeat { epoch, .. }
+            | Message::HeartbeatOk { epoch, .. }
+            | Message::StartViewChange { epoch, .. }
+            | Message::DoViewChange { epoch, .. }
+            | Message::StartView { epoch, .. }
+            | Message::GetState { epoch, .. }
+            | Message::NewState { epoch, .. } => Some(*epoch),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use crate::types::ReplicaId;
+
+/// Collects votes per key, e.g. the `PrepareOk`s of every op, and tells when a quorum of distinct replicas voted.
+///
+/// A replica counts once per key, so a duplicated or retransmitted message can never fake a quorum. The replica
+/// collecting the votes counts only if it votes itself. Every vote may carry a value, such as the log of a
+/// `DoViewChange`.
+#[derive(Debug, Clone)]
+pub struct QuorumTracker<K, V = ()> {
+    quorum: usize,
+    votes: BTreeMap<K, BTreeMap<ReplicaId, V>>,
+}
+
+impl<K: Ord, V> QuorumTracker<K, V> {
+    /// A tracker that needs `quorum` distinct votes, which need not be a majority.
+    pub fn new(quorum: usize) -> Self {
+        Self { quorum, votes: BTreeMap::new() }
+    }
+
+    pub fn quorum(&self) -> usize {
+        self.quorum
+    }
+
+    /// Changes the number of votes needed, e.g. after a reconfiguration. The votes already cast are kept.
+    pub fn set_quorum(&mut self, quorum: usize) {
+        self.quorum = quorum;
+    }
+
+    /// Records the vote of `voter` for `key`. Returns false, and keeps the first vote, if `voter` already voted.
+    pub fn vote(&mut self, key: K, voter: ReplicaId, value: V) -> bool {
+        let votes = self.votes.entry(key).or_default();
+        if votes.contains_key(&voter) {
+            return false;
+        }
+
+        votes.insert(voter, value);
+        true
+    }
+
+    pub fn has_voted(&self, key: &K, voter: ReplicaId) -> bool {
+        self.votes.get(key).is_some_and(|votes| votes.contains_key(&voter))
+    }
+
+    /// The number of distinct replicas that voted for `key`.
+    pub fn count(&self, key: &K) -> usize {
+        self.votes.get(key).map_or(0, |votes| votes.len())
+    }
+
+    pub fn has_quorum(&self, key: &K) -> bool {
+        self.count(key) >= self.quorum
+    }
+
+    /// The votes for `key`, by voter.
+    pub fn votes(&self, key: &K) -> impl Iterator<Item = (&ReplicaId, &V)> {
+        self.votes.get(key).into_iter().flatten()
+    }
+
+    /// Removes and returns the votes for `key`.
+    pub fn take(&mut self, key: &K) -> BTreeMap<ReplicaId, V> {
+        self.votes.remove(key).unwrap_or_default()
+    }
+
+    /// Forgets the votes for `key` and every key before it.
+    pub fn remove_up_to(&mut self, key: &K) {
+        self.votes.retain(|k, _| k > key);
+    }
+
+    /// The keys with at least one vote, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.votes.keys()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.votes.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.votes.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_votes_do_not_count() {
+        let mut tracker = QuorumTracker::<u64>::new(2);
+        assert!(tracker.vote(1, 0, ()));
+        assert!(!tracker.vote(1, 0, ()));
+        assert!(!tracker.has_quorum(&1));
+
+        assert!(tracker.vote(1, 2, ()));
+        assert!(tracker.has_quorum(&1));
+        assert_eq!(tracker.count(&1), 2);
+        assert_eq!(tracker.count(&2), 0);
+    }
+
+    #[test]
+    fn test_first_vote_keeps_its_value() {
+        let mut tracker = QuorumTracker::<u64, &str>::new(3);
+        tracker.vote(7, 1, "first");
+        tracker.vote(7, 1, "second");
+        tracker.vote(7, 0, "other");
+        assert_eq!(tracker.votes(&7).collect::<Vec<_>>(), vec![(&0, &"other"), (&1, &"first")]);
+
+        // A smaller quorum is reached with the same votes.
+        assert!(!tracker.has_quorum(&7));
+        tracker.set_quorum(2);
+        assert!(tracker.has_quorum(&7));
+    }
+
+    #[test]
+    fn test_remove_up_to() {
+        let mut tracker = QuorumTracker::<u64>::new(1);
+        for key in 1..=4 {
+            tracker.vote(key, 0, ());
+        }
+
+        tracker.remove_up_to(&2);
+        assert_eq!(tracker.keys().copied().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(tracker.take(&3).len(), 1);
+        assert_eq!(tracker.keys().copied().collect::<Vec<_>>(), vec![4]);
+    }
+}
@@ -1,20 +1,26 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
 use std::fmt::Debug;
 use std::rc::Rc;
 
+use crate::client_table::ClientTable;
 use crate::clock::TimerKind;
 use crate::effect::Effect;
-use crate::message::{ClientRequest, Message};
+use crate::error::ReplicaError;
+use crate::message::{Checkpoint, ClientRequest, Log, Message, Operation, ReadRequest};
+use crate::quorum::QuorumTracker;
 use crate::state_machine::StateMachine;
+use crate::storage::{DurableState, FsyncPolicy, LogStorage, MemoryLogStorage, Metadata};
 use crate::types::{OpNumber, ReplicaId};
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Status {
     Normal,
     ViewChange,
     Recovering,
     Transitioning,
+    /// The storage failed, so the replica stopped for good.
+    Failed,
 }
 
 #[derive(Debug, Clone)]
@@ -29,21 +35,149 @@ where
     pub epoch: u64,
     pub view_number: ReplicaId,
     pub status: Status,
+    /// The latest view in which this replica had `Status::Normal`.
+    pub last_normal_view: ReplicaId,
 
     pub op_number: usize,
     pub commit_number: usize,
-    pub log: Vec<(OpNumber, ClientRequest<Input, Output>)>,
-    client_table: HashMap<u64, ClientRequest<Input, Output>>,
+    /// The log entries after `checkpoint`.
+    pub log: Log<Input, Output>,
+    /// The latest snapshot of the state machine, which replaces the log entries up to it.
+    pub checkpoint: Option<Checkpoint<Output>>,
+    /// How many ops are committed between checkpoints. Checkpoints are off unless set.
+    checkpoint_interval: Option<usize>,
+    /// The latest committed request of every client, with its reply, built from the committed ops.
+    pub client_table: ClientTable<Output>,
+    /// Requires clients to register a session, and bounds the client table. Sessions are off unless set.
+    max_clients: Option<usize>,
+    /// Peer messages dropped because they cannot be valid, e.g. a reply meant for a client.
+    pub malformed_messages: u64,
+    /// The first storage failure of the current step, which stops the replica at its end.
+    storage_error: Option<String>,
 
-    pub op_ack_table: HashMap<OpNumber, Vec<ReplicaId>>,
+    /// The replicas that acknowledged every uncommitted op, the primary included.
+    pub op_ack_table: QuorumTracker<OpNumber>,
+
+    // Batching
+    batching: Batching,
+    /// The last op the primary sent a Prepare for. The ops after it form the current batch.
+    prepared_op_number: OpNumber,
+    batch_bytes: usize,
+    /// How many ops the primary may have prepared but not committed. Further ops wait in the batch.
+    max_in_flight: usize,
+
+    // Reads
+    read_mode: ReadMode,
+    pending_reads: Vec<PendingRead<Input>>,
+    /// The latest heartbeat round, which is in flight until a quorum acknowledges it.
+    read_number: u64,
+    /// The acknowledgements of every heartbeat round, by read number.
+    read_acks: QuorumTracker<u64>,
+    read_started_at: u64,
+    confirmed_read_number: u64,
+    /// Until when the primary may answer reads without a heartbeat round, under `ReadMode::Lease`.
+    lease_expiry: Option<u64>,
+    /// Until when a backup refuses to join a view change, after it acknowledged a heartbeat under `ReadMode::Lease`.
+    lease_granted_until: Option<u64>,
+
+    // View change
+    /// The votes for the view changes, by view number.
+    start_view_change_votes: QuorumTracker<ReplicaId>,
+    do_view_change_votes: QuorumTracker<ReplicaId, DoViewChangeVote<Input, Output>>,
+
+    // State transfer
+    next_state_transfer: Option<u64>,
+
+    // Reconfiguration
+    /// The configuration of the previous epoch.
+    old_configuration: Vec<ReplicaId>,
+    /// The op number of the reconfiguration that started the current epoch.
+    epoch_op_number: OpNumber,
+    /// Set while catching up with the old group to move to this epoch.
+    awaiting_epoch: Option<u64>,
+    epoch_started_votes: QuorumTracker<u64>,
+
+    // Recovery
+    recovery_nonce: Option<u64>,
+    /// The responses to our recovery, by nonce.
+    recovery_responses: QuorumTracker<u64, RecoveryResponse<Input, Output>>,
 
     pub state_machine: Rc<RefCell<dyn StateMachine<Input = Input, Output = Output>>>,
+    /// The durable copy of `log`. Entries are only acknowledged once they are durable in it.
+    pub storage: Rc<RefCell<dyn LogStorage<Input, Output>>>,
 
     // Timers
     timeout_primary_idle_commit: u64,
     next_primary_idle_commit: Option<u64>,
     timeout_backup_watchdog: u64,
     next_backup_watchdog: Option<u64>,
+    next_log_sync: Option<u64>,
+    next_batch_flush: Option<u64>,
+}
+
+/// How the primary groups client requests into a single `Message::Prepare`.
+///
+/// A batch is prepared as soon as it holds `max_ops` requests or `max_bytes` of inputs, as sized by
+/// `StateMachine::input_size`, and otherwise `flush_delay_ms` after its first request.
+#[derive(Clone, Debug)]
+pub struct Batching {
+    pub max_ops: usize,
+    pub max_bytes: usize,
+    pub flush_delay_ms: u64,
+}
+
+impl Default for Batching {
+    /// Every request is prepared on its own, right away.
+    fn default() -> Self {
+        Self { max_ops: 1, max_bytes: usize::MAX, flush_delay_ms: 0 }
+    }
+}
+
+/// How the primary makes sure it is still the primary before answering a `Message::Read`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReadMode {
+    /// Every read waits for a round of heartbeats acknowledged by a quorum, sent after the read arrived.
+    Quorum,
+    /// Reads are answered right away for `duration_ms` after a quorum acknowledged a heartbeat. Backups do not
+    /// join a view change during that time, so no other primary can commit writes. It must be shorter than the
+    /// backup watchdog timeout, and assumes the clocks of the replicas advance at about the same rate.
+    Lease { duration_ms: u64 },
+}
+
+/// A read waiting at the primary for its heartbeat round and for the ops before it to commit.
+#[derive(Debug, Clone)]
+struct PendingRead<Input> {
+    request: ReadRequest<Input>,
+    /// The op number when the read arrived. Every write the client may have seen completing is at or before it.
+    op_number: OpNumber,
+    /// The heartbeat round that confirms we were still the primary after the read arrived.
+    read_number: u64,
+}
+
+/// The state carried by a `Message::DoViewChange`, kept by the new primary until it has a quorum.
+#[derive(Debug, Clone)]
+struct DoViewChangeVote<Input, Output> {
+    log: Log<Input, Output>,
+    checkpoint: Option<Checkpoint<Output>>,
+    last_normal_view: ReplicaId,
+    op_number: usize,
+    commit_number: usize,
+}
+
+/// A `Message::RecoveryResponse` kept by a recovering replica. Only the primary fills `state`.
+#[derive(Debug, Clone)]
+struct RecoveryResponse<Input, Output> {
+    view_number: ReplicaId,
+    state: Option<LogState<Input, Output>>,
+}
+
+/// A log with the checkpoint it follows, as shipped by state transfer and recovery.
+#[derive(Debug, Clone)]
+struct LogState<Input, Output> {
+    log: Log<Input, Output>,
+    checkpoint: Option<Checkpoint<Output>>,
+    op_number: usize,
+    commit_number: usize,
 }
 
 impl<Input, Output> Replica<Input, Output>
@@ -58,6 +192,7 @@ where
     ) -> Self {
         let mut configuration = configuration.clone();
         configuration.sort();
+        let quorum = configuration.len() / 2 + 1;
         Replica {
             state_machine,
             configuration,
@@ -67,215 +202,1563 @@ where
             commit_number: 0,
             epoch: 0,
             status: Status::Normal,
+            last_normal_view: 0,
             log: Vec::new(),
-            client_table: HashMap::new(),
-            op_ack_table: HashMap::new(),
+            checkpoint: None,
+            checkpoint_interval: None,
+            client_table: ClientTable::default(),
+            max_clients: None,
+            malformed_messages: 0,
+            storage_error: None,
+            op_ack_table: QuorumTracker::new(quorum),
+            batching: Batching::default(),
+            prepared_op_number: 0,
+            batch_bytes: 0,
+            max_in_flight: 64,
+            read_mode: ReadMode::Quorum,
+            pending_reads: Vec::new(),
+            read_number: 0,
+            read_acks: QuorumTracker::new(quorum),
+            read_started_at: 0,
+            confirmed_read_number: 0,
+            lease_expiry: None,
+            lease_granted_until: None,
+            start_view_change_votes: QuorumTracker::new(quorum),
+            do_view_change_votes: QuorumTracker::new(quorum),
+            next_state_transfer: None,
+            old_configuration: Vec::new(),
+            epoch_op_number: 0,
+            awaiting_epoch: None,
+            epoch_started_votes: QuorumTracker::new(quorum),
+            recovery_nonce: None,
+            recovery_responses: QuorumTracker::new(quorum),
             timeout_primary_idle_commit: 1000,
             next_primary_idle_commit: None,
             timeout_backup_watchdog: 5000,
             next_backup_watchdog: None,
+            next_log_sync: None,
+            next_batch_flush: None,
+            storage: Rc::new(RefCell::new(MemoryLogStorage::new(FsyncPolicy::PerOp))),
+        }
+    }
+
+    /// Creates a replica whose log is persisted in `storage`, restoring what a previous run left there.
+    ///
+    /// A restored replica starts in `Status::ViewChange` in its last view. It rejoins through the next view change,
+    /// or by moving to the current view when it hears from its primary. Until then it acknowledges nothing, since
+    /// ops it had not seen committed may have been replaced while it was down.
+    pub fn with_storage(
+        configuration: Vec<ReplicaId>,
+        replica_number: ReplicaId,
+        state_machine: Rc<RefCell<dyn StateMachine<Input = Input, Output = Output>>>,
+        storage: Rc<RefCell<dyn LogStorage<Input, Output>>>,
+    ) -> std::io::Result<Self> {
+        let DurableState { metadata, checkpoint, log } = storage.borrow_mut().load()?;
+        let mut replica = Self::new(configuration, replica_number, state_machine);
+        replica.storage = storage;
+
+        if let Some(metadata) = &metadata {
+            replica.epoch = metadata.epoch;
+            replica.view_number = metadata.view_number;
+            replica.last_normal_view = metadata.last_normal_view;
+            replica.configuration = metadata.configuration.clone();
+            replica.update_quorums();
+        }
+
+        if let Some(checkpoint) = checkpoint {
+            let sm = replica.state_machine.clone();
+            let mut sm = sm.borrow_mut();
+            let Some(snapshot) = sm.as_snapshot() else {
+                let message = "the storage holds a checkpoint, but the state machine does not implement Snapshot";
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+            };
+
+            snapshot.restore(&checkpoint.data)?;
+            replica.client_table = checkpoint.client_table.clone();
+            replica.commit_number = checkpoint.op_number;
+            replica.op_number = checkpoint.op_number;
+            replica.checkpoint = Some(checkpoint);
         }
+
+        if metadata.is_some() || replica.checkpoint.is_some() || !log.is_empty() {
+            replica.op_number += log.len();
+            replica.log = log;
+            replica.status = Status::ViewChange;
+            println!("restored view_number: {:?}, op_number: {:?}, replica_number: {:?}", replica.view_number, replica.op_number, replica_number);
+        }
+
+        Ok(replica)
+    }
+
+    pub fn set_read_mode(&mut self, read_mode: ReadMode) {
+        self.read_mode = read_mode;
+    }
+
+    pub fn set_batching(&mut self, batching: Batching) {
+        self.batching = batching;
+    }
+
+    /// Bounds the window of ops the primary prepares ahead of its commit number.
+    pub fn set_max_in_flight(&mut self, ops: usize) {
+        self.max_in_flight = ops.max(1);
+    }
+
+    /// Takes a checkpoint every `interval` committed ops, if the state machine implements `Snapshot`.
+    pub fn set_checkpoint_interval(&mut self, interval: usize) {
+        self.checkpoint_interval = Some(interval);
+    }
+
+    /// Only serves clients that registered a session, and keeps at most `max_clients` of them, evicting the least
+    /// recently used one when another registers. Replicas evict as they commit, so every replica must use the same
+    /// bound.
+    pub fn set_max_clients(&mut self, max_clients: usize) {
+        self.max_clients = Some(max_clients.max(1));
     }
 
     pub fn tick(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.status == Status::Failed {
+            return vec![];
+        }
+
+        let effects = self.on_tick(now);
+        self.stop_on_storage_failure(effects)
+    }
+
+    pub fn on_message(&mut self, message: Message<Input, Output>, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.status == Status::Failed {
+            return vec![];
+        }
+
+        let effects = self.dispatch(message, now);
+        self.stop_on_storage_failure(effects)
+    }
+
+    fn on_tick(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
         let mut effects = vec![];
+        if self.next_log_sync.is_some_and(|t| now >= t) {
+            effects.extend(self.sync_log(now));
+        }
+
+        if self.next_batch_flush.is_some_and(|t| now >= t) {
+            effects.extend(self.flush_batch(now));
+        }
+
         if self.is_primary() && self.status == Status::Normal {
-            if self.next_primary_idle_commit.is_some_and(|t| now >= t) {
-                let commit = Message::Commit {
-                    op_number: self.op_number,
-                    commit_number: self.commit_number,
-                    view_number: self.view_number,
-                };
+            match self.next_primary_idle_commit {
+                Some(t) if now >= t => {
+                    let commit = Message::Commit {
+                        op_number: self.op_number,
+                        commit_number: self.commit_number,
+                        epoch: self.epoch,
+                        view_number: self.view_number,
+                    };
+
+                    effects.push(Effect::Broadcast { to: self.other_replicas(), message: commit });
+                    effects.push(self.reset_primary_idle_commit(now));
+
+                    // Retry a heartbeat round that lost messages, and keep the lease from running out.
+                    let is_leasing = matches!(self.read_mode, ReadMode::Lease { .. });
+                    if self.has_read_in_flight() || !self.pending_reads.is_empty() || is_leasing {
+                        effects.extend(self.send_heartbeat(now));
+                    }
+                }
+                None => effects.push(self.reset_primary_idle_commit(now)),
+                _ => {}
+            }
+        }
+
+        if self.status == Status::Recovering {
+            match self.next_backup_watchdog {
+                Some(t) if now >= t => effects.extend(self.send_recovery(now)),
+                None => effects.push(self.reset_backup_watchdog(now)),
+                _ => {}
+            }
+        }
 
-                effects.push(Effect::Broadcast { to: self.configuration.clone(), message: commit });
-                let at = now + self.timeout_primary_idle_commit;
-                self.next_primary_idle_commit = Some(at);
-                effects.push(Effect::SetTimer { kind: TimerKind::PrimaryIdleCommit, at });
+        if self.status == Status::Transitioning {
+            match self.next_backup_watchdog {
+                Some(t) if now >= t => effects.extend(self.retry_transition(now)),
+                None => effects.push(self.reset_backup_watchdog(now)),
+                _ => {}
             }
         }
 
-        if !self.is_primary() && self.status == Status::Normal {
-            if self.next_backup_watchdog.is_some_and(|t| now >= t) {
-                // TODO: Implement View Change Protocol here
-                println!("TODO: Implement View Change Protocol here");
+        // Backups watch the primary, and every replica watches a view change that does not complete.
+        let is_watching = self.status == Status::ViewChange || (self.status == Status::Normal && !self.is_primary());
+        if is_watching {
+            match self.next_backup_watchdog {
+                // We promised the primary not to replace it while its lease lasts, so the view change waits for the
+                // lease to run out.
+                Some(t) if now >= t => match self.lease_granted_until.filter(|until| now < *until) {
+                    Some(at) => {
+                        self.next_backup_watchdog = Some(at);
+                        effects.push(Effect::SetTimer { kind: TimerKind::BackupWatchdog, at });
+                    }
+                    None => effects.extend(self.start_view_change(self.view_number + 1, now)),
+                },
+                None => effects.push(self.reset_backup_watchdog(now)),
+                _ => {}
             }
         }
 
         effects
     }
 
-    pub fn on_message(&mut self, message: Message<Input, Output>, now: u64) -> Vec<Effect<Input, Output>> {
+    /// Starts the recovery protocol for a replica that restarted without its state.
+    ///
+    /// The replica stays in `Status::Recovering` until it hears from a quorum, including the primary of the latest view.
+    pub fn recover(&mut self, nonce: u64, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.status == Status::Failed {
+            return vec![];
+        }
+
+        self.status = Status::Recovering;
+        self.recovery_nonce = Some(nonce);
+        self.recovery_responses.clear();
+        self.send_recovery(now)
+    }
+
+    fn dispatch(&mut self, message: Message<Input, Output>, now: u64) -> Vec<Effect<Input, Output>> {
+        // Clients get an answer in any status, which tells them to retry or where the primary is.
+        let message = match message {
+            Message::Request(request) => return self.on_request(request, now),
+            Message::Read(request) => return self.on_read(request, now),
+            message => message,
+        };
+
+        if self.status == Status::Recovering && !matches!(message, Message::RecoveryResponse { .. }) {
+            return vec![];
+        }
+
+        // State transfer is the only traffic that crosses epochs, to bring replicas into the newer one.
+        if let Some(epoch) = message.epoch()
+            && epoch != self.epoch
+            && !matches!(message, Message::GetState { .. } | Message::NewState { .. })
+        {
+            return self.on_other_epoch(epoch, message);
+        }
+
+        if self.is_malformed(&message) {
+            println!("dropping malformed message: {:?}, replica_number: {:?}", message.name(), self.replica_number);
+            self.malformed_messages += 1;
+            return vec![];
+        }
+
+        if self.status == Status::Transitioning && !matches!(
+            message,
+            Message::StartEpoch { .. } | Message::EpochStarted { .. } | Message::GetState { .. } | Message::NewState { .. }
+        ) {
+            return vec![];
+        }
+
+        match message {
+            Message::Prepare { view_number, op_number, commit_number, requests, .. } =>
+                self.on_prepare(requests, view_number, op_number, commit_number, now),
+            Message::PrepareOk { view_number, replica_number, op_number, commit_number, .. } =>
+                self.on_prepare_ok(view_number, replica_number, op_number, commit_number, now),
+            Message::Commit { op_number, commit_number, view_number, .. } =>
+                self.on_commit(op_number, commit_number, view_number, now),
+            Message::Heartbeat { view_number, commit_number, read_number, .. } =>
+                self.on_heartbeat(view_number, commit_number, read_number, now),
+            Message::HeartbeatOk { view_number, read_number, replica_number, .. } =>
+                self.on_heartbeat_ok(view_number, read_number, replica_number, now),
+            Message::StartViewChange { view_number, replica_number, .. } =>
+                self.on_start_view_change(view_number, replica_number, now),
+            Message::DoViewChange { view_number, log, checkpoint, last_normal_view, op_number, commit_number, replica_number, .. } => {
+                let vote = DoViewChangeVote { log, checkpoint, last_normal_view, op_number, commit_number };
+                self.on_do_view_change(view_number, replica_number, vote, now)
+            }
+            Message::StartView { view_number, log, checkpoint, op_number, commit_number, .. } =>
+                self.on_start_view(view_number, log, checkpoint, op_number, commit_number, now),
+            Message::GetState { epoch, view_number, op_number, replica_number } =>
+                self.on_get_state(epoch, view_number, op_number, replica_number),
+            Message::NewState { epoch, view_number, log, checkpoint, op_number, commit_number } =>
+                self.on_new_state(epoch, view_number, LogState { log, checkpoint, op_number, commit_number }, now),
+            Message::StartEpoch { epoch, op_number: _, old_configuration, configuration, replica_number } =>
+                self.on_start_epoch(epoch, old_configuration, configuration, replica_number, now),
+            Message::EpochStarted { epoch, replica_number } =>
+                self.on_epoch_started(epoch, replica_number),
+            Message::Recovery { replica_number, nonce } =>
+                self.on_recovery(replica_number, nonce),
+            Message::RecoveryResponse { view_number, nonce, log, checkpoint, op_number, commit_number, replica_number } => {
+                let state = match (log, op_number, commit_number) {
+                    (Some(log), Some(op_number), Some(commit_number)) =>
+                        Some(LogState { log, checkpoint, op_number, commit_number }),
+                    _ => None,
+                };
+                self.on_recovery_response(replica_number, nonce, RecoveryResponse { view_number, state }, now)
+            }
+            // Client traffic was answered above, and what only clients receive was dropped as malformed.
+            Message::Request(_)
+            | Message::Read(_)
+            | Message::Reply { .. }
+            | Message::Error { .. }
+            | Message::Connect { .. } => vec![],
+        }
+    }
+
+    /// Whether a peer message cannot be valid: one only clients receive, a vote from outside the configuration, or
+    /// op numbers that do not add up.
+    fn is_malformed(&self, message: &Message<Input, Output>) -> bool {
+        let is_member = |replica_number: &ReplicaId| self.configuration.contains(replica_number);
+        let is_consistent = |log: &Log<Input, Output>, op_number: usize, commit_number: usize| {
+            commit_number <= op_number
+                && log.windows(2).all(|w| w[1].0 == w[0].0 + 1)
+                && log.first().is_none_or(|(n, _)| *n > 0)
+                && log.last().is_none_or(|(n, _)| *n <= op_number)
+        };
+
         match message {
-            Message::Request { 0: request } => self.on_request(request, now),
-            Message::Prepare { op: _, view_number, op_number, commit_number , request } =>
-                self.on_prepare(request, view_number, op_number, commit_number, now),
-            Message::PrepareOk { view_number, replica_number, op_number, commit_number } =>
-                self.on_prepare_ok(view_number, replica_number, op_number, commit_number),
-            Message::Commit { op_number, commit_number, view_number } => {
-                self.on_commit(op_number, commit_number, view_number);
-                vec![]
+            Message::Reply { .. } | Message::Error { .. } | Message::Connect { .. } => true,
+            Message::Prepare { op_number, commit_number, requests, .. } => {
+                requests.len() > *op_number || commit_number > op_number
+            }
+            Message::Commit { op_number, commit_number, .. } => commit_number > op_number,
+            Message::PrepareOk { replica_number, .. }
+            | Message::HeartbeatOk { replica_number, .. }
+            | Message::StartViewChange { replica_number, .. } => !is_member(replica_number),
+            Message::DoViewChange { log, op_number, commit_number, replica_number, .. } => {
+                !is_member(replica_number) || !is_consistent(log, *op_number, *commit_number)
             }
-            m => panic!("unexpected message: {:?}", m)
+            Message::StartView { log, op_number, commit_number, .. }
+            | Message::NewState { log, op_number, commit_number, .. } => !is_consistent(log, *op_number, *commit_number),
+            Message::RecoveryResponse { log: Some(log), op_number: Some(op), commit_number: Some(commit), .. } => {
+                !is_consistent(log, *op, *commit)
+            }
+            _ => false,
         }
     }
 
     fn on_request(&mut self, request: ClientRequest<Input, Output>, now: u64) -> Vec<Effect<Input, Output>> {
-        if !self.is_primary() {
-            return vec![];
+        if let Some(error) = self.check_primary() {
+            return self.reply_error(request.client_id, error);
+        }
+
+        // No request is accepted after a reconfiguration until the new epoch starts.
+        if self.has_pending_reconfiguration() {
+            return self.reply_error(request.client_id, ReplicaError::ReconfigurationPending);
+        }
+
+        if let Operation::Reconfigure { epoch, configuration } = &request.op {
+            if *epoch != self.epoch {
+                return self.reply_error(request.client_id, ReplicaError::WrongEpoch { epoch: self.epoch });
+            }
+            if configuration.is_empty() {
+                return self.reply_error(request.client_id, ReplicaError::EmptyConfiguration);
+            }
+        }
+
+        // The client id of a `Register` is its nonce rather than a session. A retried one that was committed, maybe
+        // in an earlier view, gets the client id of the session it opened back.
+        let is_register = matches!(request.op, Operation::Register);
+        if is_register {
+            if let Some(client_id) = self.client_table.client_of(request.client_id) {
+                let reply = Message::Reply {
+                    client_id,
+                    view_number: self.view_number,
+                    request_id: request.request_number,
+                    result: None,
+                };
+                return vec![Effect::Reply { client_id: request.client_id, message: reply }];
+            }
+        } else if !self.has_session(request.client_id) {
+            return self.reply_error(request.client_id, ReplicaError::UnknownSession { client_id: request.client_id });
         }
 
-        if let Some(last_request) = self.get_last_request_from_client(request.client_id.clone()) {
+        // A retried request that was committed, maybe in an earlier view, is answered from the client table.
+        if let Some(last_request) = self.client_table.get(request.client_id).filter(|_| !is_register) {
             if request.request_number < last_request.request_number {
-                return vec![];
+                let error = ReplicaError::StaleRequest {
+                    client_id: request.client_id,
+                    request_number: request.request_number,
+                    last_request_number: last_request.request_number,
+                };
+                return self.reply_error(request.client_id, error);
             }
 
             if request.request_number == last_request.request_number {
                 let reply = Message::Reply {
-                    client_id: request.client_id.clone(),
+                    client_id: request.client_id,
                     view_number: self.view_number,
                     request_id: request.request_number,
                     result: last_request.result.clone(),
                 };
 
-                return vec![Effect::Reply { client_id: request.client_id.clone(), message: reply }];
+                return vec![Effect::Reply { client_id: request.client_id, message: reply }];
             }
         };
 
+        // A resent request that is still being prepared is dropped, its reply follows the commit. A `Register` is only
+        // matched by another `Register`, as its nonce may equal a session.
+        let in_progress = self.log.iter().any(|(op_number, r)| {
+            *op_number > self.commit_number
+                && r.client_id == request.client_id
+                && r.request_number == request.request_number
+                && matches!(r.op, Operation::Register) == is_register
+        });
+        if in_progress {
+            return vec![];
+        }
+
+        self.batch_bytes += match &request.op {
+            Operation::Apply(op) => self.state_machine.borrow().input_size(op),
+            Operation::Reconfigure { .. } | Operation::Register => std::mem::size_of_val(&request.op),
+        };
+        let is_reconfiguration = matches!(request.op, Operation::Reconfigure { .. });
+
         self.op_number += 1;
-        if self.log.len() + 1 == self.op_number {
-            self.log.push((self.op_number, request.clone()));
+        self.append(self.op_number, request);
+        self.op_ack_table.vote(self.op_number, self.replica_number, ());
+
+        let mut effects = self.schedule_log_sync(now);
+
+        // Nothing is accepted after a reconfiguration, so its batch is complete.
+        let batch_size = self.op_number - self.prepared_op_number;
+        if batch_size >= self.batching.max_ops || self.batch_bytes >= self.batching.max_bytes || is_reconfiguration {
+            effects.extend(self.flush_batch(now));
+        } else if self.next_batch_flush.is_none() {
+            let at = now + self.batching.flush_delay_ms;
+            self.next_batch_flush = Some(at);
+            effects.push(Effect::SetTimer { kind: TimerKind::BatchFlush, at });
         }
 
-        let mut effects = vec![];
+        effects
+    }
 
-        let prepare = Message::Prepare {
-            op: request.op.clone(),
+    /// Starts a heartbeat round, or repeats the one in flight.
+    fn send_heartbeat(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        if !self.has_read_in_flight() {
+            self.read_number += 1;
+            self.read_acks.clear();
+            self.read_acks.vote(self.read_number, self.replica_number, ());
+            self.read_started_at = now;
+        }
+
+        let heartbeat = Message::Heartbeat {
+            epoch: self.epoch,
             view_number: self.view_number,
-            op_number: self.op_number,
             commit_number: self.commit_number,
-            request: Box::new(request.clone()),
+            read_number: self.read_number,
         };
 
-        effects.push(Effect::Broadcast { to: self.configuration.clone(), message: prepare });
+        vec![Effect::Broadcast { to: self.other_replicas(), message: heartbeat }]
+    }
 
-        let at = now + self.timeout_primary_idle_commit;
-        self.next_primary_idle_commit = Some(at);
-        effects.push(Effect::SetTimer { kind: TimerKind::PrimaryIdleCommit, at });
+    /// Answers the reads whose heartbeat round completed and whose preceding ops are committed.
+    fn serve_reads(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        let has_lease = self.lease_expiry.is_some_and(|t| now < t);
+        let (ready, pending) = std::mem::take(&mut self.pending_reads).into_iter().partition::<Vec<_>, _>(|read| {
+            (read.read_number <= self.confirmed_read_number || has_lease) && read.op_number <= self.commit_number
+        });
+        self.pending_reads = pending;
 
-        effects
+        let sm = self.state_machine.clone();
+        let sm = sm.borrow();
+        ready.into_iter().map(|PendingRead { request, .. }| {
+            let message = match sm.read(&request.op) {
+                Some(result) => Message::Reply {
+                    client_id: request.client_id,
+                    view_number: self.view_number,
+                    request_id: request.request_number,
+                    result: Some(result),
+                },
+                None => Message::Error { error: ReplicaError::NotReadOnly },
+            };
+
+            Effect::Reply { client_id: request.client_id, message }
+        }).collect()
+    }
+
+    fn has_read_in_flight(&self) -> bool {
+        self.read_number > self.confirmed_read_number
+    }
+
+    /// Drops the reads and heartbeat rounds of a previous view, so that only acknowledgements for this view count.
+    fn clear_reads(&mut self) {
+        self.pending_reads.clear();
+        self.confirmed_read_number = self.read_number;
+        self.read_acks.clear();
+        self.lease_expiry = None;
+    }
+
+    /// Sends one Prepare for every op appended since the last one, as far as the in-flight window allows.
+    fn flush_batch(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        self.next_batch_flush = None;
+        self.batch_bytes = 0;
+        let last_op_number = self.op_number.min(self.commit_number + self.max_in_flight);
+        if !self.is_primary() || self.status != Status::Normal || self.prepared_op_number >= last_op_number {
+            return vec![];
+        }
+
+        let requests = self
+            .log_after(self.prepared_op_number)
+            .into_iter()
+            .take(last_op_number - self.prepared_op_number)
+            .map(|(_, request)| request)
+            .collect();
+        self.prepared_op_number = last_op_number;
+
+        let prepare = Message::Prepare {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            op_number: last_op_number,
+            commit_number: self.commit_number,
+            requests,
+        };
+
+        vec![
+            Effect::Broadcast { to: self.other_replicas(), message: prepare },
+            self.reset_primary_idle_commit(now),
+        ]
     }
 
     fn on_prepare(
         &mut self,
-        request: Box<ClientRequest<Input, Output>>,
+        requests: Vec<ClientRequest<Input, Output>>,
         view_number: ReplicaId,
         op_number: usize,
         commit_number: usize,
         now: u64
     ) -> Vec<Effect<Input, Output>> {
-        if !self.is_same_view(view_number) {
+        if self.is_newer_view(view_number) {
+            self.enter_view(view_number);
+        }
+
+        if !self.is_same_view(view_number) || self.status != Status::Normal || self.is_primary() {
             return vec![];
         }
 
         let mut effects = vec![];
 
-        if self.log.len() + 1 == op_number {
-            println!("pushing op_number: {:?}, replica_number: {:?}, request: {:?}", op_number, self.replica_number, request);
-            self.log.push((op_number, *request));
+        // Batches may overlap with what we already have, after a retransmission or a state transfer.
+        let first_op_number = (op_number + 1).saturating_sub(requests.len());
+        for (entry_op_number, request) in (first_op_number..).zip(requests) {
+            if entry_op_number == self.op_number + 1 {
+                println!("pushing op_number: {:?}, replica_number: {:?}, request: {:?}", entry_op_number, self.replica_number, request);
+                self.append(entry_op_number, request);
+                self.op_number = entry_op_number;
+            }
         }
 
-        self.commit_number = commit_number;
-
-        let prepare_ok = Message::PrepareOk {
-            view_number: self.view_number,
-            replica_number: self.replica_number,
-            op_number,
-            commit_number,
-        };
+        effects.push(self.reset_backup_watchdog(now));
 
-        effects.push(Effect::Send { to: self.view_number, message: prepare_ok });
-
-        let at = now + self.timeout_backup_watchdog;
-        self.next_backup_watchdog = Some(at);
-        effects.push(Effect::SetTimer { kind: TimerKind::BackupWatchdog, at });
-        effects.push(Effect::ApplyCommited { op_number: self.op_number });
+        // The prepare does not follow our log, so we cannot acknowledge it until we catch up.
+        if op_number > self.op_number {
+            effects.extend(self.send_get_state(now));
+            return effects;
+        }
 
+        effects.extend(self.execute_committed(commit_number, now));
+        effects.extend(self.send_prepare_ok(op_number, now));
         effects
     }
 
-    fn on_prepare_ok(&mut self, view_number: ReplicaId, replica_number: ReplicaId, op_number: usize, commit_number: usize) -> Vec<Effect<Input, Output>> {
-        if !self.is_same_view(view_number) || !self.is_primary() {
+    fn on_prepare_ok(
+        &mut self,
+        view_number: ReplicaId,
+        replica_number: ReplicaId,
+        op_number: usize,
+        _commit_number: usize,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        if !self.is_same_view(view_number) || !self.is_primary() || self.status != Status::Normal {
             return vec![];
         }
 
-        if op_number <= self.commit_number {
+        if op_number <= self.commit_number || op_number > self.prepared_op_number {
             return vec![];
         }
 
-        self.op_ack_table.entry(op_number).or_insert(vec![]).push(replica_number);
-
-        let quorum = self.get_quorum();
-        if self.op_ack_table.get(&op_number).unwrap_or(&vec![]).len() < quorum {
+        // A backup only acknowledges an op once it has every op before it, so the ack counts for all of them.
+        // PrepareOks may arrive in any order, and a duplicated one must not count twice.
+        let mut counted = false;
+        for acked_op_number in self.commit_number + 1..=op_number {
+            counted |= self.op_ack_table.vote(acked_op_number, replica_number, ());
+        }
+        if !counted {
             return vec![];
         }
 
-        let mut effects = vec![];
-        let (result, request) = self.commit_op(op_number);
+        let committed = (self.commit_number + 1..=op_number).rev().find(|op_number| self.op_ack_table.has_quorum(op_number));
 
-        let reply = Message::Reply {
-            client_id: request.client_id.clone(),
-            view_number: self.view_number,
-            request_id: request.request_number,
-            result: Some(result),
-        };
+        // Our own vote only counts once the op is durable here too.
+        match committed {
+            Some(op_number) => self.execute_committed(op_number.min(self.durable_op_number()), now),
+            None => vec![],
+        }
+    }
+
+    fn on_commit(&mut self, _op_number: OpNumber, commit_number: usize, view_number: ReplicaId, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.is_newer_view(view_number) {
+            self.enter_view(view_number);
+        }
 
-        effects.push(Effect::Reply { client_id: request.client_id.clone(), message: reply });
+        if !self.is_same_view(view_number) || self.is_primary() || self.status != Status::Normal {
+            return vec![];
+        }
 
+        let mut effects = vec![self.reset_backup_watchdog(now)];
+        effects.extend(self.execute_committed(commit_number, now));
+        if commit_number > self.op_number {
+            effects.extend(self.send_get_state(now));
+        }
         effects
     }
 
-    fn on_commit(&mut self, op_number: OpNumber, commit_number: usize, view_number: ReplicaId) {
-        if !self.is_same_view(view_number) || self.is_primary() {
-            return;
+    fn on_read(&mut self, request: ReadRequest<Input>, now: u64) -> Vec<Effect<Input, Output>> {
+        if let Some(error) = self.check_primary() {
+            return self.reply_error(request.client_id, error);
         }
 
-        if op_number == self.op_number {
-            return;
+        if !self.has_session(request.client_id) {
+            return self.reply_error(request.client_id, ReplicaError::UnknownSession { client_id: request.client_id });
         }
 
-        let _ = self.commit_op(op_number);
+        // Under a lease, the read only waits for the ops before it to commit.
+        let read_number = if self.lease_expiry.is_some_and(|t| now < t) { self.confirmed_read_number } else { self.read_number + 1 };
+        self.pending_reads.push(PendingRead { request, op_number: self.op_number, read_number });
+
+        let mut effects = self.serve_reads(now);
+        if !self.has_read_in_flight() && read_number > self.read_number {
+            effects.extend(self.send_heartbeat(now));
+        }
+        effects
     }
 
-    #[inline]
-    fn is_primary(&self) -> bool {
-        self.view_number == self.replica_number
+    fn on_heartbeat(&mut self, view_number: ReplicaId, commit_number: usize, read_number: u64, now: u64) -> Vec<Effect<Input, Output>> {
+        let mut effects = self.on_commit(self.op_number, commit_number, view_number, now);
+        if !self.is_same_view(view_number) || self.is_primary() || self.status != Status::Normal {
+            return effects;
+        }
+
+        if let ReadMode::Lease { duration_ms } = self.read_mode {
+            self.lease_granted_until = Some(now + duration_ms);
+        }
+
+        let heartbeat_ok = Message::HeartbeatOk {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            read_number,
+            replica_number: self.replica_number,
+        };
+
+        effects.push(Effect::Send { to: self.primary_of(self.view_number), message: heartbeat_ok });
+        effects
     }
 
-    fn is_same_view(&self, view_number: ReplicaId) -> bool {
-        self.view_number == view_number
+    fn on_heartbeat_ok(&mut self, view_number: ReplicaId, read_number: u64, replica_number: ReplicaId, now: u64) -> Vec<Effect<Input, Output>> {
+        if !self.is_same_view(view_number) || !self.is_primary() || self.status != Status::Normal {
+            return vec![];
+        }
+
+        if read_number != self.read_number || !self.has_read_in_flight() {
+            return vec![];
+        }
+
+        self.read_acks.vote(read_number, replica_number, ());
+        if !self.read_acks.has_quorum(&read_number) {
+            return vec![];
+        }
+
+        self.confirmed_read_number = read_number;
+        if let ReadMode::Lease { duration_ms } = self.read_mode {
+            // The backups granted the lease after we sent the heartbeat, so counting from then is conservative.
+            self.lease_expiry = Some(self.read_started_at + duration_ms);
+        }
+
+        let mut effects = self.serve_reads(now);
+        if self.pending_reads.iter().any(|read| read.read_number > self.read_number) {
+            effects.extend(self.send_heartbeat(now));
+        }
+        effects
     }
 
-    fn get_last_request_from_client(&self, client_id: u64) -> Option<ClientRequest<Input, Output>> {
-        self.client_table.get(&client_id).cloned()
+    fn on_get_state(&mut self, epoch: u64, view_number: ReplicaId, op_number: usize, replica_number: ReplicaId) -> Vec<Effect<Input, Output>> {
+        // Replicas of a newer epoch serve the replicas catching up with the reconfiguration that started it.
+        let is_serving = if epoch == self.epoch {
+            self.is_same_view(view_number) && self.status == Status::Normal
+        } else {
+            epoch < self.epoch
+        };
+
+        if !is_serving || op_number > self.op_number {
+            return vec![];
+        }
+
+        let new_state = Message::NewState {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            log: self.log_after(op_number),
+            checkpoint: self.checkpoint.clone().filter(|c| op_number < c.op_number),
+            op_number: self.op_number,
+            commit_number: self.commit_number,
+        };
+
+        vec![Effect::Send { to: replica_number, message: new_state }]
     }
 
-    fn get_quorum(&self) -> usize {
-        self.configuration.len() / 2 + 1
+    fn on_new_state(
+        &mut self,
+        epoch: u64,
+        view_number: ReplicaId,
+        state: LogState<Input, Output>,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        let LogState { log, checkpoint, op_number, commit_number } = state;
+        let is_current = epoch == self.epoch && self.is_same_view(view_number) && self.status == Status::Normal;
+        let is_catching_up = self.status == Status::Transitioning && self.awaiting_epoch.is_some_and(|e| epoch >= e);
+        if !(is_current || is_catching_up) || op_number <= self.op_number {
+            return vec![];
+        }
+
+        // The ops we asked for were compacted away, so we start over from the checkpoint that replaced them.
+        if let Some(checkpoint) = checkpoint
+            && checkpoint.op_number > self.commit_number
+        {
+            self.restore_checkpoint(checkpoint);
+        }
+
+        // Another prepare or state transfer may have extended our log since we asked, so skip what we already have.
+        for (entry_op_number, request) in log {
+            if entry_op_number == self.op_number + 1 {
+                self.append(entry_op_number, request);
+                self.op_number = entry_op_number;
+            }
+        }
+        self.next_state_transfer = None;
+
+        println!("transferred state up to op_number: {:?}, replica_number: {:?}", self.op_number, self.replica_number);
+        let mut effects = self.execute_committed(commit_number, now);
+
+        if self.status == Status::Normal && self.op_number > self.commit_number {
+            effects.extend(self.send_prepare_ok(self.op_number, now));
+        }
+
+        effects.push(self.reset_backup_watchdog(now));
+        effects
     }
 
-    // TODO: Validate if it needs to do more operations here
-    pub fn commit_op(&mut self, op_number: OpNumber) -> (Output, ClientRequest<Input, Output>) {
-        // TODO: Validate how exactly we should retrieve the op_number to be committed.
-        // From the original implementation, seems that it does op_number - 1. Why? Not sure yet.
-        println!("committing op_number: {:?}, log: {:?}, replica_number: {:?}", op_number, self.log, self.replica_number);
-        let op_number = if op_number == 0 { 0 } else { op_number - 1 };
-        println!("op_number: {:?}", op_number);
-        let (_op_number, request) = self.log.get(op_number).unwrap();
-        let sm = self.state_machine.clone();
-        let result = sm.borrow_mut().apply(request.op.clone());
-        let mut request = request.clone();
-        request.result = Some(result.clone());
-        self.client_table.insert(request.client_id.clone(), request.clone());
-        (result, request)
+    fn on_start_view_change(&mut self, view_number: ReplicaId, replica_number: ReplicaId, now: u64) -> Vec<Effect<Input, Output>> {
+        if view_number < self.view_number || (view_number == self.view_number && self.status != Status::ViewChange) {
+            return vec![];
+        }
+
+        // We promised the primary not to replace it while its lease lasts.
+        if view_number > self.view_number && self.lease_granted_until.is_some_and(|t| now < t) {
+            return vec![];
+        }
+
+        let mut effects = vec![];
+        if view_number > self.view_number {
+            effects.extend(self.start_view_change(view_number, now));
+        }
+
+        if !self.start_view_change_votes.vote(view_number, replica_number, ()) {
+            return effects;
+        }
+
+        // Only the vote that completes the quorum sends the DoViewChange, so it is sent once per view.
+        if self.start_view_change_votes.count(&view_number) == self.start_view_change_votes.quorum() {
+            effects.extend(self.send_do_view_change(now));
+        }
+
+        effects
+    }
+
+    fn on_do_view_change(
+        &mut self,
+        view_number: ReplicaId,
+        replica_number: ReplicaId,
+        vote: DoViewChangeVote<Input, Output>,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        if view_number < self.view_number || (view_number == self.view_number && self.status != Status::ViewChange) {
+            return vec![];
+        }
+
+        if self.primary_of(view_number) != self.replica_number {
+            return vec![];
+        }
+
+        let mut effects = vec![];
+        if view_number > self.view_number {
+            effects.extend(self.start_view_change(view_number, now));
+        }
+
+        self.do_view_change_votes.vote(view_number, replica_number, vote);
+
+        let has_own_vote = self.do_view_change_votes.has_voted(&view_number, self.replica_number);
+        if has_own_vote && self.do_view_change_votes.has_quorum(&view_number) {
+            effects.extend(self.start_view(now));
+        }
+
+        effects
+    }
+
+    fn on_start_view(
+        &mut self,
+        view_number: ReplicaId,
+        log: Log<Input, Output>,
+        checkpoint: Option<Checkpoint<Output>>,
+        op_number: usize,
+        commit_number: usize,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        if view_number < self.view_number || (view_number == self.view_number && self.status == Status::Normal) {
+            return vec![];
+        }
+
+        self.view_number = view_number;
+        self.last_normal_view = view_number;
+        self.status = Status::Normal;
+        self.replace_log(log, checkpoint);
+        self.op_number = op_number;
+        self.clear_view_change();
+        self.next_primary_idle_commit = None;
+        self.persist_metadata();
+
+        let mut effects = vec![self.reset_backup_watchdog(now)];
+        effects.extend(self.execute_committed(commit_number, now));
+
+        if self.op_number > self.commit_number {
+            effects.extend(self.send_prepare_ok(self.op_number, now));
+        }
+
+        effects
+    }
+
+    fn on_start_epoch(
+        &mut self,
+        epoch: u64,
+        old_configuration: Vec<ReplicaId>,
+        configuration: Vec<ReplicaId>,
+        replica_number: ReplicaId,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        // A leaving replica that missed our EpochStarted asks again.
+        if epoch == self.epoch && self.status == Status::Normal && !self.configuration.contains(&replica_number) {
+            let epoch_started = Message::EpochStarted { epoch: self.epoch, replica_number: self.replica_number };
+            return vec![Effect::Send { to: replica_number, message: epoch_started }];
+        }
+
+        if epoch <= self.epoch || self.awaiting_epoch == Some(epoch) {
+            return vec![];
+        }
+
+        // Catch up with the old group up to the reconfiguration, whose execution moves us to the new epoch.
+        println!("awaiting epoch: {:?}, configuration: {:?}, replica_number: {:?}", epoch, configuration, self.replica_number);
+        let mut old_configuration = old_configuration;
+        old_configuration.sort();
+        self.epoch = epoch - 1;
+        self.configuration = old_configuration;
+        self.update_quorums();
+        self.status = Status::Transitioning;
+        self.awaiting_epoch = Some(epoch);
+        self.truncate_log(self.commit_number);
+        self.op_number = self.commit_number;
+        self.clear_view_change();
+        self.op_ack_table.clear();
+        self.next_primary_idle_commit = None;
+        self.next_state_transfer = None;
+        self.persist_metadata();
+
+        let mut effects = vec![self.reset_backup_watchdog(now)];
+        effects.extend(self.send_get_state(now));
+        effects
+    }
+
+    fn on_epoch_started(&mut self, epoch: u64, replica_number: ReplicaId) -> Vec<Effect<Input, Output>> {
+        if epoch != self.epoch || !self.is_leaving() || !self.configuration.contains(&replica_number) {
+            return vec![];
+        }
+
+        self.epoch_started_votes.vote(epoch, replica_number, ());
+        if !self.epoch_started_votes.has_quorum(&epoch) {
+            return vec![];
+        }
+
+        println!("leaving the configuration of epoch: {:?}, replica_number: {:?}", self.epoch, self.replica_number);
+        vec![Effect::Shutdown]
+    }
+
+    /// Replies to a message from a replica still in an older epoch with the reconfiguration it missed.
+    fn on_other_epoch(&mut self, epoch: u64, message: Message<Input, Output>) -> Vec<Effect<Input, Output>> {
+        if epoch > self.epoch || self.awaiting_epoch.is_some() {
+            return vec![];
+        }
+
+        let replica_number = match message {
+            Message::PrepareOk { replica_number, .. }
+            | Message::HeartbeatOk { replica_number, .. }
+            | Message::StartViewChange { replica_number, .. }
+            | Message::DoViewChange { replica_number, .. } => replica_number,
+            _ => return vec![],
+        };
+
+        vec![Effect::Send { to: replica_number, message: self.start_epoch_message() }]
+    }
+
+    fn on_recovery(&mut self, replica_number: ReplicaId, nonce: u64) -> Vec<Effect<Input, Output>> {
+        if self.status != Status::Normal || replica_number == self.replica_number {
+            return vec![];
+        }
+
+        let (log, checkpoint, op_number, commit_number) = if self.is_primary() {
+            (Some(self.log.clone()), self.checkpoint.clone(), Some(self.op_number), Some(self.commit_number))
+        } else {
+            (None, None, None, None)
+        };
+
+        let recovery_response = Message::RecoveryResponse {
+            view_number: self.view_number,
+            nonce,
+            log,
+            checkpoint,
+            op_number,
+            commit_number,
+            replica_number: self.replica_number,
+        };
+
+        vec![Effect::Send { to: replica_number, message: recovery_response }]
+    }
+
+    fn on_recovery_response(
+        &mut self,
+        replica_number: ReplicaId,
+        nonce: u64,
+        response: RecoveryResponse<Input, Output>,
+        now: u64,
+    ) -> Vec<Effect<Input, Output>> {
+        if self.status != Status::Recovering || self.recovery_nonce != Some(nonce) {
+            return vec![];
+        }
+
+        self.recovery_responses.vote(nonce, replica_number, response);
+        if !self.recovery_responses.has_quorum(&nonce) {
+            return vec![];
+        }
+
+        let Some(view_number) = self.recovery_responses.votes(&nonce).map(|(_, r)| r.view_number).max() else {
+            return vec![];
+        };
+
+        let primary = self.primary_of(view_number);
+        let Some(RecoveryResponse { state: Some(LogState { log, checkpoint, op_number, commit_number }), .. }) = self.recovery_responses
+            .votes(&nonce)
+            .find(|(replica_number, r)| **replica_number == primary && r.view_number == view_number)
+            .map(|(_, r)| r.clone())
+        else {
+            return vec![];
+        };
+
+        println!("recovered at view_number: {:?}, op_number: {:?}, replica_number: {:?}", view_number, op_number, self.replica_number);
+        self.view_number = view_number;
+        self.last_normal_view = view_number;
+        self.status = Status::Normal;
+        self.replace_log(log, checkpoint);
+        self.op_number = op_number;
+        self.recovery_nonce = None;
+        self.recovery_responses.clear();
+        self.persist_metadata();
+
+        let mut effects = vec![self.reset_backup_watchdog(now)];
+        effects.extend(self.execute_committed(commit_number, now));
+        effects
+    }
+
+    /// Asks the primary for the log entries after our op number, or the whole old group while moving to a new epoch.
+    /// Requests are rate limited so that a burst of prepares arriving out of order does not trigger one transfer each.
+    fn send_get_state(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.next_state_transfer.is_some_and(|t| now < t) {
+            return vec![];
+        }
+        self.next_state_transfer = Some(now + self.timeout_primary_idle_commit);
+
+        let get_state = Message::GetState {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            op_number: self.op_number,
+            replica_number: self.replica_number,
+        };
+
+        let to = if self.status == Status::Transitioning {
+            self.other_replicas()
+        } else {
+            vec![self.primary_of(self.view_number)]
+        };
+
+        vec![Effect::Broadcast { to, message: get_state }]
+    }
+
+    /// Moves to a view that we learned about from its primary, without taking part in its view change.
+    ///
+    /// Ops after our commit number may have been replaced in that view, so they are dropped and fetched again.
+    fn enter_view(&mut self, view_number: ReplicaId) {
+        println!("entering view_number: {:?}, replica_number: {:?}", view_number, self.replica_number);
+        self.truncate_log(self.commit_number);
+        self.op_number = self.commit_number;
+        self.view_number = view_number;
+        self.last_normal_view = view_number;
+        self.status = Status::Normal;
+        self.clear_view_change();
+        self.op_ack_table.clear();
+        self.next_primary_idle_commit = None;
+        self.next_state_transfer = None;
+        self.persist_metadata();
+    }
+
+    fn send_recovery(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        let Some(nonce) = self.recovery_nonce else {
+            return vec![];
+        };
+
+        let recovery = Message::Recovery {
+            replica_number: self.replica_number,
+            nonce,
+        };
+
+        vec![
+            Effect::Broadcast { to: self.other_replicas(), message: recovery },
+            self.reset_backup_watchdog(now),
+        ]
+    }
+
+    fn start_view_change(&mut self, view_number: ReplicaId, now: u64) -> Vec<Effect<Input, Output>> {
+        println!("starting view change to view_number: {:?}, replica_number: {:?}", view_number, self.replica_number);
+        self.view_number = view_number;
+        self.status = Status::ViewChange;
+        self.clear_view_change();
+        self.start_view_change_votes.vote(view_number, self.replica_number, ());
+        self.op_ack_table.clear();
+        self.next_primary_idle_commit = None;
+        self.persist_metadata();
+
+        let start_view_change = Message::StartViewChange {
+            epoch: self.epoch,
+            view_number,
+            replica_number: self.replica_number,
+        };
+
+        vec![
+            Effect::Broadcast { to: self.other_replicas(), message: start_view_change },
+            self.reset_backup_watchdog(now),
+        ]
+    }
+
+    fn send_do_view_change(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        let vote = DoViewChangeVote {
+            log: self.log.clone(),
+            checkpoint: self.checkpoint.clone(),
+            last_normal_view: self.last_normal_view,
+            op_number: self.op_number,
+            commit_number: self.commit_number,
+        };
+
+        let primary = self.primary_of(self.view_number);
+        if primary == self.replica_number {
+            return self.on_do_view_change(self.view_number, self.replica_number, vote, now);
+        }
+
+        let do_view_change = Message::DoViewChange {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            log: vote.log,
+            checkpoint: vote.checkpoint,
+            last_normal_view: vote.last_normal_view,
+            op_number: vote.op_number,
+            commit_number: vote.commit_number,
+            replica_number: self.replica_number,
+        };
+
+        vec![Effect::Send { to: primary, message: do_view_change }]
+    }
+
+    /// Installs the most up-to-date log among the DoViewChange votes and resumes as the primary of the new view.
+    fn start_view(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        let votes = self.do_view_change_votes.take(&self.view_number);
+        let commit_number = votes.values().map(|v| v.commit_number).max().unwrap_or(self.commit_number);
+        let Some(best) = votes.into_values().max_by_key(|v| (v.last_normal_view, v.op_number)) else {
+            return vec![];
+        };
+
+        println!("starting view_number: {:?} as primary, replica_number: {:?}", self.view_number, self.replica_number);
+        self.replace_log(best.log, best.checkpoint);
+        self.op_number = best.op_number;
+        self.prepared_op_number = self.op_number;
+        self.clear_reads();
+        self.status = Status::Normal;
+        self.last_normal_view = self.view_number;
+        self.clear_view_change();
+        self.next_backup_watchdog = None;
+        self.persist_metadata();
+
+        self.op_ack_table.clear();
+        for op_number in commit_number + 1..=self.op_number {
+            self.op_ack_table.vote(op_number, self.replica_number, ());
+        }
+
+        let start_view = Message::StartView {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            log: self.log.clone(),
+            checkpoint: self.checkpoint.clone(),
+            op_number: self.op_number,
+            commit_number,
+        };
+
+        let mut effects = vec![Effect::Broadcast { to: self.other_replicas(), message: start_view }];
+        effects.extend(self.execute_committed(commit_number.min(self.durable_op_number()), now));
+        effects.extend(self.schedule_log_sync(now));
+        effects.push(self.reset_primary_idle_commit(now));
+        effects
+    }
+
+    /// Moves to the next epoch once its reconfiguration is executed. Replicas that are not part of the new
+    /// configuration stay in `Status::Transitioning` until a quorum of the new group has started the epoch.
+    fn start_epoch(&mut self, configuration: Vec<ReplicaId>, now: u64) -> Vec<Effect<Input, Output>> {
+        let mut effects = vec![];
+        if self.is_primary() && self.status == Status::Normal {
+            // Backups of the old group learn that the reconfiguration committed from this last commit.
+            let commit = Message::Commit {
+                op_number: self.op_number,
+                commit_number: self.commit_number,
+                epoch: self.epoch,
+                view_number: self.view_number,
+            };
+
+            effects.push(Effect::Broadcast { to: self.other_replicas(), message: commit });
+        }
+
+        let mut configuration = configuration;
+        configuration.sort();
+        configuration.dedup();
+        let was_member = self.configuration.contains(&self.replica_number);
+        self.old_configuration = std::mem::replace(&mut self.configuration, configuration);
+        self.update_quorums();
+        self.epoch += 1;
+        self.epoch_op_number = self.commit_number;
+        self.prepared_op_number = self.op_number;
+        self.clear_reads();
+        self.view_number = 0;
+        self.last_normal_view = 0;
+        self.awaiting_epoch = None;
+        self.epoch_started_votes.clear();
+        self.clear_view_change();
+        self.op_ack_table.clear();
+        self.next_state_transfer = None;
+        self.next_primary_idle_commit = None;
+        self.next_backup_watchdog = None;
+        self.persist_metadata();
+        println!("starting epoch: {:?}, configuration: {:?}, replica_number: {:?}", self.epoch, self.configuration, self.replica_number);
+
+        if self.is_leaving() {
+            self.status = Status::Transitioning;
+            effects.extend(self.retry_transition(now));
+            return effects;
+        }
+
+        self.status = Status::Normal;
+        let joining = self.configuration.iter().copied().filter(|r| !self.old_configuration.contains(r)).collect::<Vec<_>>();
+        if was_member && !joining.is_empty() {
+            effects.push(Effect::Broadcast { to: joining, message: self.start_epoch_message() });
+        }
+
+        let leaving = self.old_configuration.iter().copied().filter(|r| !self.configuration.contains(r)).collect::<Vec<_>>();
+        if !leaving.is_empty() {
+            let epoch_started = Message::EpochStarted { epoch: self.epoch, replica_number: self.replica_number };
+            effects.push(Effect::Broadcast { to: leaving, message: epoch_started });
+        }
+
+        if self.is_primary() {
+            effects.push(self.reset_primary_idle_commit(now));
+        } else {
+            effects.push(self.reset_backup_watchdog(now));
+        }
+
+        effects
+    }
+
+    /// Repeats the step of a reconfiguration this replica is waiting on.
+    fn retry_transition(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        let mut effects = vec![self.reset_backup_watchdog(now)];
+        if self.awaiting_epoch.is_some() {
+            effects.extend(self.send_get_state(now));
+        } else if self.is_leaving() {
+            effects.push(Effect::Broadcast { to: self.configuration.clone(), message: self.start_epoch_message() });
+        }
+        effects
+    }
+
+    fn start_epoch_message(&self) -> Message<Input, Output> {
+        Message::StartEpoch {
+            epoch: self.epoch,
+            op_number: self.epoch_op_number,
+            old_configuration: self.old_configuration.clone(),
+            configuration: self.configuration.clone(),
+            replica_number: self.replica_number,
+        }
+    }
+
+    fn clear_view_change(&mut self) {
+        self.start_view_change_votes.clear();
+        self.do_view_change_votes.clear();
+    }
+
+    fn reset_primary_idle_commit(&mut self, now: u64) -> Effect<Input, Output> {
+        let at = now + self.timeout_primary_idle_commit;
+        self.next_primary_idle_commit = Some(at);
+        Effect::SetTimer { kind: TimerKind::PrimaryIdleCommit, at }
+    }
+
+    fn reset_backup_watchdog(&mut self, now: u64) -> Effect<Input, Output> {
+        let at = now + self.timeout_backup_watchdog;
+        self.next_backup_watchdog = Some(at);
+        Effect::SetTimer { kind: TimerKind::BackupWatchdog, at }
+    }
+
+    /// Acknowledges the ops up to `op_number` to the primary, or waits for the next sync if they are not durable yet.
+    fn send_prepare_ok(&mut self, op_number: OpNumber, now: u64) -> Vec<Effect<Input, Output>> {
+        if op_number > self.durable_op_number() {
+            return self.schedule_log_sync(now);
+        }
+
+        let prepare_ok = Message::PrepareOk {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            replica_number: self.replica_number,
+            op_number,
+            commit_number: self.commit_number,
+        };
+
+        vec![Effect::Send { to: self.primary_of(self.view_number), message: prepare_ok }]
+    }
+
+    /// Arms the sync timer when the storage holds entries that are not durable yet.
+    fn schedule_log_sync(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        if self.next_log_sync.is_some() || self.durable_op_number() >= self.op_number {
+            return vec![];
+        }
+
+        let Some(delay) = self.storage.borrow().sync_delay() else {
+            return vec![];
+        };
+
+        let at = now + delay;
+        self.next_log_sync = Some(at);
+        vec![Effect::SetTimer { kind: TimerKind::LogSync, at }]
+    }
+
+    /// Syncs the storage, and sends the acknowledgements or commits that were waiting on it.
+    fn sync_log(&mut self, now: u64) -> Vec<Effect<Input, Output>> {
+        self.next_log_sync = None;
+        let result = self.storage.borrow_mut().sync();
+        if let Err(err) = result {
+            self.fail(err);
+            return vec![];
+        }
+
+        let durable_op_number = self.durable_op_number();
+        if self.status != Status::Normal || durable_op_number <= self.commit_number {
+            return vec![];
+        }
+
+        if !self.is_primary() {
+            return self.send_prepare_ok(durable_op_number, now);
+        }
+
+        let committed = (self.commit_number + 1..=durable_op_number).rev().find(|op_number| self.op_ack_table.has_quorum(op_number));
+
+        match committed {
+            Some(op_number) => self.execute_committed(op_number, now),
+            None => vec![],
+        }
+    }
+
+    fn durable_op_number(&self) -> OpNumber {
+        self.storage.borrow().durable_op_number().min(self.op_number)
+    }
+
+    /// Appends to the log, writing the entry through to the storage.
+    fn append(&mut self, op_number: OpNumber, request: ClientRequest<Input, Output>) {
+        if self.last_op_number() + 1 != op_number {
+            return;
+        }
+
+        let result = self.storage.borrow_mut().append(op_number, &request);
+        if let Err(err) = result {
+            self.fail(err);
+            return;
+        }
+        self.log.push((op_number, request));
+    }
+
+    fn truncate_log(&mut self, op_number: OpNumber) {
+        let result = self.storage.borrow_mut().truncate(op_number);
+        if let Err(err) = result {
+            self.fail(err);
+            return;
+        }
+        self.log.truncate(op_number.saturating_sub(self.checkpoint_op_number()));
+    }
+
+    /// Installs a log received from another replica. Committed ops are the same in every log, so only the entries
+    /// after our commit number are rewritten in the storage, unless the log follows a newer checkpoint than that.
+    fn replace_log(&mut self, log: Log<Input, Output>, checkpoint: Option<Checkpoint<Output>>) {
+        self.truncate_log(self.commit_number);
+        self.op_number = self.commit_number;
+        if let Some(checkpoint) = checkpoint
+            && checkpoint.op_number > self.commit_number
+        {
+            self.restore_checkpoint(checkpoint);
+        }
+
+        for (op_number, request) in log {
+            self.append(op_number, request);
+        }
+    }
+
+    /// The log entries after `op_number`.
+    fn log_after(&self, op_number: OpNumber) -> Log<Input, Output> {
+        let start = op_number.saturating_sub(self.checkpoint_op_number()).min(self.log.len());
+        self.log[start..].to_vec()
+    }
+
+    fn last_op_number(&self) -> OpNumber {
+        self.checkpoint_op_number() + self.log.len()
+    }
+
+    fn checkpoint_op_number(&self) -> OpNumber {
+        self.checkpoint.as_ref().map_or(0, |c| c.op_number)
+    }
+
+    /// Snapshots the state machine once `checkpoint_interval` ops were committed since the last checkpoint, and
+    /// drops the log entries it covers.
+    fn maybe_checkpoint(&mut self) {
+        let Some(interval) = self.checkpoint_interval else {
+            return;
+        };
+
+        if self.commit_number < self.checkpoint_op_number() + interval {
+            return;
+        }
+
+        let Some(data) = self.state_machine.borrow_mut().as_snapshot().map(|snapshot| snapshot.snapshot()) else {
+            return;
+        };
+
+        println!("checkpoint at op_number: {:?}, replica_number: {:?}", self.commit_number, self.replica_number);
+        let checkpoint = Checkpoint { op_number: self.commit_number, data, client_table: self.client_table.clone() };
+        let result = self.storage.borrow_mut().save_checkpoint(&checkpoint);
+        if let Err(err) = result {
+            self.fail(err);
+            return;
+        }
+        self.log.drain(..self.commit_number - self.checkpoint_op_number());
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Replaces the state machine and the log up to the checkpoint with a checkpoint from another replica.
+    /// A replica whose state machine cannot restore it, or that gets a snapshot it cannot read, fails, as it cannot
+    /// catch up without it.
+    fn restore_checkpoint(&mut self, checkpoint: Checkpoint<Output>) {
+        println!("restoring checkpoint at op_number: {:?}, replica_number: {:?}", checkpoint.op_number, self.replica_number);
+        let sm = self.state_machine.clone();
+        let mut sm = sm.borrow_mut();
+        let Some(snapshot) = sm.as_snapshot() else {
+            let message = "received a checkpoint, but the state machine does not implement Snapshot";
+            self.fail(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+            return;
+        };
+
+        if let Err(err) = snapshot.restore(&checkpoint.data) {
+            self.fail(err);
+            return;
+        }
+
+        self.client_table = checkpoint.client_table.clone();
+        let result = self.storage.borrow_mut().save_checkpoint(&checkpoint);
+        if let Err(err) = result {
+            self.fail(err);
+            return;
+        }
+        self.log.retain(|(op_number, _)| *op_number > checkpoint.op_number);
+        self.commit_number = checkpoint.op_number;
+        self.op_number = self.op_number.max(checkpoint.op_number);
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Persists the view and configuration, so that a restarted replica does not vote in views it already left.
+    fn persist_metadata(&mut self) {
+        let metadata = Metadata {
+            epoch: self.epoch,
+            view_number: self.view_number,
+            last_normal_view: self.last_normal_view,
+            configuration: self.configuration.clone(),
+        };
+
+        let result = self.storage.borrow_mut().save_metadata(&metadata);
+        if let Err(err) = result {
+            self.fail(err);
+        }
+    }
+
+    /// Records a failure of the storage, or of restoring a checkpoint. The replica can no longer tell what is durable,
+    /// or what its state is, so it stops at the end of the step.
+    fn fail(&mut self, err: std::io::Error) {
+        println!("replica failed: {:?}, replica_number: {:?}", err, self.replica_number);
+        self.storage_error.get_or_insert(err.to_string());
+    }
+
+    /// Stops the replica if its storage failed during the step. Its effects are dropped, as they may count on
+    /// writes that did not happen, e.g. a PrepareOk for an entry that is not durable.
+    fn stop_on_storage_failure(&mut self, effects: Vec<Effect<Input, Output>>) -> Vec<Effect<Input, Output>> {
+        let Some(error) = self.storage_error.take() else {
+            return effects;
+        };
+
+        self.status = Status::Failed;
+        vec![Effect::Failed { error }]
+    }
+
+    #[inline]
+    pub fn is_primary(&self) -> bool {
+        self.primary_of(self.view_number) == self.replica_number
+    }
+
+    /// The primary of a view is chosen round-robin over the sorted configuration.
+    fn primary_of(&self, view_number: ReplicaId) -> ReplicaId {
+        self.configuration[view_number as usize % self.configuration.len()]
+    }
+
+    fn other_replicas(&self) -> Vec<ReplicaId> {
+        self.configuration.iter().copied().filter(|r| *r != self.replica_number).collect()
+    }
+
+    fn is_same_view(&self, view_number: ReplicaId) -> bool {
+        self.view_number == view_number
+    }
+
+    /// Whether a message from the primary of `view_number` shows that we missed the start of its view.
+    fn is_newer_view(&self, view_number: ReplicaId) -> bool {
+        self.status != Status::Recovering
+            && (view_number > self.view_number || (view_number == self.view_number && self.status == Status::ViewChange))
+    }
+
+    /// Whether this replica was removed by the reconfiguration that started the current epoch.
+    fn is_leaving(&self) -> bool {
+        self.awaiting_epoch.is_none() && !self.configuration.contains(&self.replica_number)
+    }
+
+    fn has_pending_reconfiguration(&self) -> bool {
+        self.log.iter().any(|(op_number, request)| {
+            *op_number > self.commit_number && matches!(request.op, Operation::Reconfigure { .. })
+        })
+    }
+
+    /// Whether the client may send requests: it has a session, or sessions are off.
+    fn has_session(&self, client_id: u64) -> bool {
+        self.max_clients.is_none() || self.client_table.get(client_id).is_some()
+    }
+
+    /// Why this replica cannot take client requests, unless it is the primary of a normal view.
+    fn check_primary(&self) -> Option<ReplicaError> {
+        if self.status != Status::Normal {
+            return Some(ReplicaError::WrongView { view_number: self.view_number, status: self.status.clone() });
+        }
+
+        if !self.is_primary() {
+            let primary = self.primary_of(self.view_number);
+            return Some(ReplicaError::NotPrimary { view_number: self.view_number, primary });
+        }
+
+        None
+    }
+
+    fn reply_error(&self, client_id: u64, error: ReplicaError) -> Vec<Effect<Input, Output>> {
+        vec![Effect::Reply { client_id, message: Message::Error { error } }]
+    }
+
+    fn get_quorum(&self) -> usize {
+        self.configuration.len() / 2 + 1
+    }
+
+    /// Sizes every quorum after the configuration changed.
+    fn update_quorums(&mut self) {
+        let quorum = self.get_quorum();
+        self.op_ack_table.set_quorum(quorum);
+        self.read_acks.set_quorum(quorum);
+        self.start_view_change_votes.set_quorum(quorum);
+        self.do_view_change_votes.set_quorum(quorum);
+        self.epoch_started_votes.set_quorum(quorum);
+        self.recovery_responses.set_quorum(quorum);
+    }
+
+    /// Executes every op in the log up to `commit_number`, in order, that was not executed yet. The primary replies
+    /// to the client of each op.
+    fn execute_committed(&mut self, commit_number: usize, now: u64) -> Vec<Effect<Input, Output>> {
+        let mut effects = vec![];
+        while self.commit_number < commit_number.min(self.op_number) {
+            let op_number = self.commit_number + 1;
+            let is_primary = self.is_primary() && self.status == Status::Normal;
+            let (result, request, client_id) = self.commit_op(op_number);
+            self.commit_number = op_number;
+            effects.push(Effect::ApplyCommited { op_number });
+
+            if is_primary {
+                let reply = Message::Reply {
+                    client_id,
+                    view_number: self.view_number,
+                    request_id: request.request_number,
+                    result,
+                };
+
+                effects.push(Effect::Reply { client_id: request.client_id, message: reply });
+            }
+
+            // Reconfigurations from earlier epochs are replayed by replicas catching up, and have no effect.
+            if let Operation::Reconfigure { epoch, configuration } = request.op
+                && epoch == self.epoch
+            {
+                effects.extend(self.start_epoch(configuration, now));
+            }
+        }
+        self.maybe_checkpoint();
+        self.op_ack_table.remove_up_to(&self.commit_number);
+        if self.is_primary() && self.status == Status::Normal {
+            if !self.pending_reads.is_empty() {
+                effects.extend(self.serve_reads(now));
+            }
+
+            // The commits made room in the window for ops that waited in a complete batch.
+            if self.prepared_op_number < self.op_number && self.next_batch_flush.is_none() {
+                effects.extend(self.flush_batch(now));
+            }
+        }
+        effects
+    }
+
+    /// Executes the op at `op_number`, and returns its result, its request, and the client id its reply carries.
+    fn commit_op(&mut self, op_number: OpNumber) -> (Option<Output>, ClientRequest<Input, Output>, u64) {
+        println!("committing op_number: {:?}, replica_number: {:?}", op_number, self.replica_number);
+        let (_op_number, request) = self.log.get(op_number - self.checkpoint_op_number() - 1).unwrap();
+        let result = match &request.op {
+            Operation::Apply(op) => {
+                let sm = self.state_machine.clone();
+                Some(sm.borrow_mut().apply(op.clone()))
+            }
+            Operation::Reconfigure { .. } | Operation::Register => None,
+        };
+        let mut request = request.clone();
+        request.result = result.clone();
+
+        // The op of a client evicted after it was prepared is executed all the same, but does not bring the client
+        // back, so its retries are rejected.
+        let mut client_id = request.client_id;
+        if matches!(request.op, Operation::Register) {
+            client_id = self.client_table.register(request.client_id, op_number, self.max_clients);
+        } else if self.has_session(request.client_id) {
+            self.client_table.record(request.client_id, request.request_number, result.clone(), op_number);
+        }
+        (result, request, client_id)
     }
 }
@@ -5,4 +5,28 @@ pub trait StateMachine: Debug + 'static {
     type Output: Clone;
 
     fn apply(&mut self, input: Self::Input) -> Self::Output;
+
+    /// Answers `input` without changing the state, or returns `None` if it is not a read.
+    fn read(&self, _input: &Self::Input) -> Option<Self::Output> {
+        None
+    }
+
+    /// The size of an input in bytes, which bounds the batches of requests the primary prepares at once.
+    fn input_size(&self, input: &Self::Input) -> usize {
+        std::mem::size_of_val(input)
+    }
+
+    /// Returns the state machine as a `Snapshot`, if it implements it. Replicas only checkpoint state machines that do.
+    fn as_snapshot(&mut self) -> Option<&mut dyn Snapshot<Input = Self::Input, Output = Self::Output>> {
+        None
+    }
+}
+
+/// A state machine that can be saved and restored, which lets replicas drop the log prefix it covers.
+pub trait Snapshot: StateMachine {
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replaces the whole state with one returned by `snapshot`. A snapshot that cannot be read, e.g. from an
+    /// incompatible version, is an `InvalidData` error, and leaves the state as it was.
+    fn restore(&mut self, snapshot: &[u8]) -> std::io::Result<()>;
 }
@@ -0,0 +1,200 @@
+use std::fmt::Debug;
+
+use crate::message::{Checkpoint, ClientRequest, Log};
+use crate::types::{OpNumber, ReplicaId};
+
+/// When appended log entries are synced, and so when a replica may acknowledge them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// Every append is synced before it counts as durable.
+    PerOp,
+    /// Appends are synced once `max_ops` are pending, or `max_delay_ms` after the first pending one.
+    Batched { max_ops: usize, max_delay_ms: u64 },
+    /// Appends are never synced, and count as durable right away.
+    None,
+}
+
+/// The replica state, besides the log, that must survive a restart.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    pub epoch: u64,
+    pub view_number: ReplicaId,
+    pub last_normal_view: ReplicaId,
+    pub configuration: Vec<ReplicaId>,
+}
+
+/// Everything a `LogStorage` holds, as read back by a restarting replica.
+#[derive(Clone, Debug)]
+pub struct DurableState<Input, Output> {
+    pub metadata: Option<Metadata>,
+    pub checkpoint: Option<Checkpoint<Output>>,
+    /// The entries after the checkpoint.
+    pub log: Log<Input, Output>,
+}
+
+impl<Input, Output> Default for DurableState<Input, Output> {
+    fn default() -> Self {
+        Self { metadata: None, checkpoint: None, log: Vec::new() }
+    }
+}
+
+/// The durable copy of `Replica::log`.
+///
+/// The replica keeps working on its in-memory log, and writes every change through to the storage. It only
+/// acknowledges entries up to `durable_op_number`.
+pub trait LogStorage<Input, Output>: Debug {
+    fn append(&mut self, op_number: OpNumber, request: &ClientRequest<Input, Output>) -> std::io::Result<()>;
+
+    /// Drops every entry after `op_number`.
+    fn truncate(&mut self, op_number: OpNumber) -> std::io::Result<()>;
+
+    /// Persists the metadata. It is durable when this returns, whatever the fsync policy.
+    fn save_metadata(&mut self, metadata: &Metadata) -> std::io::Result<()>;
+
+    /// Persists a checkpoint, and drops the entries it covers. The checkpoint is durable when this returns.
+    fn save_checkpoint(&mut self, checkpoint: &Checkpoint<Output>) -> std::io::Result<()>;
+
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> std::io::Result<()>;
+
+    /// The highest op number that is durable under the fsync policy.
+    fn durable_op_number(&self) -> OpNumber;
+
+    /// How long the replica may wait before calling `sync` when some entries are not durable yet.
+    fn sync_delay(&self) -> Option<u64>;
+
+    /// Reads back what a previous run left.
+    fn load(&mut self) -> std::io::Result<DurableState<Input, Output>>;
+}
+
+/// Counts the appends that are not synced yet, and tells when the policy asks for a sync.
+#[derive(Clone, Debug)]
+pub(crate) struct SyncState {
+    pub policy: FsyncPolicy,
+    pub pending: usize,
+}
+
+impl SyncState {
+    pub fn new(policy: FsyncPolicy) -> Self {
+        Self { policy, pending: 0 }
+    }
+
+    /// Records an append, and returns whether it must be synced right away.
+    pub fn on_append(&mut self) -> bool {
+        self.pending += 1;
+        match self.policy {
+            FsyncPolicy::PerOp => true,
+            FsyncPolicy::Batched { max_ops, .. } => self.pending >= max_ops,
+            FsyncPolicy::None => false,
+        }
+    }
+
+    pub fn sync_delay(&self) -> Option<u64> {
+        match self.policy {
+            FsyncPolicy::Batched { max_delay_ms, .. } => Some(max_delay_ms),
+            _ => None,
+        }
+    }
+}
+
+/// A `LogStorage` that lives in memory, and loses everything when the process exits.
+///
+/// It follows the fsync policy like a file would, so `durable_state` is what would have survived a crash.
+#[derive(Clone, Debug)]
+pub struct MemoryLogStorage<Input, Output> {
+    state: DurableState<Input, Output>,
+    durable_op_number: OpNumber,
+    sync_state: SyncState,
+}
+
+impl<Input: Clone, Output: Clone> MemoryLogStorage<Input, Output> {
+    pub fn new(policy: FsyncPolicy) -> Self {
+        Self {
+            state: DurableState::default(),
+            durable_op_number: 0,
+            sync_state: SyncState::new(policy),
+        }
+    }
+
+    /// Starts from what a previous run left, as returned by `durable_state`.
+    pub fn with_state(policy: FsyncPolicy, state: DurableState<Input, Output>) -> Self {
+        Self {
+            durable_op_number: last_op_number(&state.log, state.checkpoint.as_ref()),
+            state,
+            sync_state: SyncState::new(policy),
+        }
+    }
+
+    /// What would have survived a crash, leaving out the entries that were not durable under the fsync policy.
+    pub fn durable_state(&self) -> DurableState<Input, Output> {
+        let mut state = self.state.clone();
+        truncate_log(&mut state.log, self.durable_op_number);
+        state
+    }
+}
+
+impl<Input, Output> LogStorage<Input, Output> for MemoryLogStorage<Input, Output>
+where
+    Input: Clone + Debug,
+    Output: Clone + Debug,
+{
+    fn append(&mut self, op_number: OpNumber, request: &ClientRequest<Input, Output>) -> std::io::Result<()> {
+        truncate_log(&mut self.state.log, op_number - 1);
+        self.state.log.push((op_number, request.clone()));
+        if self.sync_state.on_append() {
+            self.sync()?;
+        }
+        if self.sync_state.policy == FsyncPolicy::None {
+            self.durable_op_number = op_number;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, op_number: OpNumber) -> std::io::Result<()> {
+        truncate_log(&mut self.state.log, op_number);
+        self.durable_op_number = self.durable_op_number.min(op_number);
+        Ok(())
+    }
+
+    fn save_metadata(&mut self, metadata: &Metadata) -> std::io::Result<()> {
+        // Like a file, syncing the metadata makes the entries written before it durable too.
+        self.state.metadata = Some(metadata.clone());
+        self.sync()
+    }
+
+    fn save_checkpoint(&mut self, checkpoint: &Checkpoint<Output>) -> std::io::Result<()> {
+        self.state.log.retain(|(op_number, _)| *op_number > checkpoint.op_number);
+        self.state.checkpoint = Some(checkpoint.clone());
+        self.durable_op_number = self.durable_op_number.max(checkpoint.op_number);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_state.pending = 0;
+        self.durable_op_number = last_op_number(&self.state.log, self.state.checkpoint.as_ref());
+        Ok(())
+    }
+
+    fn durable_op_number(&self) -> OpNumber {
+        self.durable_op_number
+    }
+
+    fn sync_delay(&self) -> Option<u64> {
+        self.sync_state.sync_delay()
+    }
+
+    fn load(&mut self) -> std::io::Result<DurableState<Input, Output>> {
+        Ok(self.durable_state())
+    }
+}
+
+/// Drops the entries after `op_number` from a log that may not start at the first op.
+pub(crate) fn truncate_log<Input, Output>(log: &mut Log<Input, Output>, op_number: OpNumber) {
+    let len = log.partition_point(|(n, _)| *n <= op_number);
+    log.truncate(len);
+}
+
+pub(crate) fn last_op_number<Input, Output>(log: &Log<Input, Output>, checkpoint: Option<&Checkpoint<Output>>) -> OpNumber {
+    log.last().map(|(n, _)| *n).or(checkpoint.map(|c| c.op_number)).unwrap_or(0)
+}
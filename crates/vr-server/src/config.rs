@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// The latest version of the configuration file format.
+const VERSION: u64 = 1;
+
+/// The cluster configuration file, see `examples/cluster.toml`.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub version: u64,
+    /// The replicas of the cluster. A replica number is its index in this list.
+    #[serde(rename = "replica")]
+    pub replicas: Vec<ReplicaConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplicaConfig {
+    pub address: String,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(data: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let config: Config = toml::from_str(data)?;
+        if config.version > VERSION {
+            return Err(format!("unsupported configuration version: {}", config.version).into());
+        }
+        Ok(config)
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.replicas.iter().map(|r| r.address.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples/cluster.toml");
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.version, VERSION);
+        assert_eq!(config.addresses(), ["127.0.0.1:8001", "127.0.0.1:8002", "127.0.0.1:8003"]);
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        // A file without a version predates the field, and parses as version 0.
+        let config = Config::parse("[[replica]]\naddress = \"a:1\"\n").unwrap();
+        assert_eq!((config.version, config.addresses()), (0, vec!["a:1".to_string()]));
+
+        let err = Config::parse("version = 2\n[[replica]]\naddress = \"a:1\"\n").unwrap_err();
+        assert_eq!(err.to_string(), "unsupported configuration version: 2");
+        assert!(Config::parse("version = 1\n").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use clap::{Parser, ValueEnum};
+use config::Config;
+use server::Server;
+use state::KvStore;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use vr_replica::file_storage::FileLogStorage;
+use vr_replica::replica::{Batching, ReadMode, Replica};
+use vr_replica::storage::FsyncPolicy;
+
+mod config;
+mod server;
+mod state;
+
+#[derive(Parser)]
+struct Args {
+    /// The cluster configuration file.
+    #[clap(short, long)]
+    config: PathBuf,
+    /// The index of this replica in the `[[replica]]` list of the configuration file.
+    #[clap(short, long)]
+    index: usize,
+    /// Runs the recovery protocol before serving, for a replica that restarted.
+    #[clap(long)]
+    recover: bool,
+    /// Persists the log in this directory, and restores it on start.
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// When log entries are synced to disk, with `--data-dir`.
+    #[clap(long, value_enum, default_value = "per-op")]
+    fsync: Fsync,
+    /// How many ops are committed between snapshots of the store, after which the log before them is dropped.
+    #[clap(long, default_value_t = 10_000)]
+    checkpoint_interval: usize,
+    /// The most client requests the primary prepares at once.
+    #[clap(long, default_value_t = 64)]
+    batch_max_ops: usize,
+    /// How long the primary waits for more requests before preparing a batch, in milliseconds.
+    #[clap(long, default_value_t = 1)]
+    batch_delay_ms: u64,
+    /// Answers GETs under a lease of this many milliseconds, instead of after a round of heartbeats.
+    #[clap(long)]
+    read_lease_ms: Option<u64>,
+    /// Requires clients to register, and keeps at most this many sessions, after which the least recently used one
+    /// is evicted. Must be the same on every replica. Sessions are off unless set.
+    #[clap(long)]
+    max_clients: Option<usize>,
+}
+
+/// The most bytes of commands the primary prepares at once.
+const BATCH_MAX_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Fsync {
+    PerOp,
+    Batched,
+    None,
+}
+
+impl From<Fsync> for FsyncPolicy {
+    fn from(fsync: Fsync) -> Self {
+        match fsync {
+            Fsync::PerOp => FsyncPolicy::PerOp,
+            Fsync::Batched => FsyncPolicy::Batched { max_ops: 64, max_delay_ms: 5 },
+            Fsync::None => FsyncPolicy::None,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = Config::load(&args.config).unwrap();
+    let addresses = config.addresses();
+    let Some(address) = addresses.get(args.index).cloned() else {
+        eprintln!("ERROR: no replica at index {} in {:?}", args.index, args.config);
+        std::process::exit(1);
+    };
+
+    let configuration = (0..addresses.len() as u64).collect::<Vec<_>>();
+    let state = Rc::new(RefCell::new(KvStore::default()));
+    let mut replica = match &args.data_dir {
+        Some(dir) => {
+            let storage = FileLogStorage::open(dir, args.fsync.into()).unwrap();
+            Replica::with_storage(configuration, args.index as u64, state, Rc::new(RefCell::new(storage))).unwrap()
+        }
+        None => Replica::new(configuration, args.index as u64, state),
+    };
+    replica.set_checkpoint_interval(args.checkpoint_interval);
+    if let Some(max_clients) = args.max_clients {
+        replica.set_max_clients(max_clients);
+    }
+    replica.set_batching(Batching {
+        max_ops: args.batch_max_ops,
+        max_bytes: BATCH_MAX_BYTES,
+        flush_delay_ms: args.batch_delay_ms,
+    });
+    if let Some(duration_ms) = args.read_lease_ms {
+        replica.set_read_mode(ReadMode::Lease { duration_ms });
+    }
+
+    let listener = TcpListener::bind(&address).await.unwrap();
+    println!("replica {} listening on {}", args.index, address);
+
+    let (tx, rx) = mpsc::channel(1024);
+    tokio::task::spawn(server::serve(listener, tx));
+    Server::new(replica, addresses).run(rx, args.recover).await;
+}
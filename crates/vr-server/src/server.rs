@@ -0,0 +1,466 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use vr_replica::codec::{self, FrameCodec};
+use vr_replica::effect::Effect;
+use vr_replica::message::{ClientRequest, Message, Operation, ReadRequest};
+use vr_replica::replica::Replica;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub type KvMessage = Message<Vec<String>, String>;
+
+/// How long a client request waits for the replica to reply.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages wait for a peer connection before new ones are dropped.
+const PEER_QUEUE_SIZE: usize = 1024;
+
+/// Set in the client id of a `Register`, which is its nonce. Sessions are op numbers, which never reach the top bit, so
+/// the reply to a registration is never mistaken for the reply to a session with the same id.
+const REGISTER: u64 = 1 << 63;
+
+/// Set in the request number of a read, so that its reply is told apart from the reply to a request with the same
+/// number. The client gets its own number back.
+const READ: usize = 1 << (usize::BITS - 1);
+
+/// What the network tasks hand over to the replica event loop.
+pub enum Command {
+    Message(KvMessage),
+    Request { request: ClientRequest<Vec<String>, String>, reply: oneshot::Sender<KvMessage> },
+    Read { request: ReadRequest<Vec<String>>, reply: oneshot::Sender<KvMessage> },
+    Connect { reply: oneshot::Sender<ConnectData> },
+}
+
+/// The body of a `/connect` response, as read by `vr_proxy::Proxy`.
+#[derive(Debug, Serialize)]
+pub struct ConnectData {
+    configuration: Vec<String>,
+    current_view: usize,
+    epoch: usize,
+}
+
+/// The body of a `/register` request, as sent by `vr_proxy::Proxy`.
+#[derive(Debug, Deserialize)]
+struct RegisterData {
+    /// A random number the reply is routed by, until the client has its id.
+    nonce: u64,
+}
+
+/// The body of a `/` request, as sent by `vr_proxy::Proxy`.
+#[derive(Debug, Deserialize)]
+struct RequestData {
+    /// The id the client got from `/register`.
+    client_id: u64,
+    op: Vec<String>,
+    request_number: u64,
+}
+
+/// The body of a response that is not a message of the replica, e.g. after a timeout.
+#[derive(Debug, Serialize)]
+struct ErrorData<'a> {
+    error: &'a str,
+}
+
+/// Drives a `Replica` from the commands of the network tasks and its timers, and executes its effects.
+pub struct Server {
+    replica: Replica<Vec<String>, String>,
+    /// The address of each replica, indexed by replica number.
+    addresses: Vec<String>,
+    started_at: Instant,
+    timers: BTreeSet<u64>,
+    /// The reply channel of every request, read and registration waiting on the replica, by the client id and request
+    /// number it carries, tagged with `REGISTER` and `READ`.
+    pending_replies: HashMap<(u64, usize), oneshot::Sender<KvMessage>>,
+    /// The outgoing queue of the connection to each peer, opened on the first message.
+    peers: HashMap<u64, mpsc::Sender<KvMessage>>,
+    /// How many messages were dropped because the queue of their peer was full or its connection task gone.
+    dropped_messages: u64,
+}
+
+impl Server {
+    pub fn new(replica: Replica<Vec<String>, String>, addresses: Vec<String>) -> Self {
+        Self {
+            replica,
+            addresses,
+            started_at: Instant::now(),
+            timers: BTreeSet::new(),
+            pending_replies: HashMap::new(),
+            peers: HashMap::new(),
+            dropped_messages: 0,
+        }
+    }
+
+    pub async fn run(mut self, mut commands: mpsc::Receiver<Command>, recover: bool) {
+        let now = self.now();
+        let effects = if recover {
+            let nonce = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            self.replica.recover(nonce, now)
+        } else {
+            self.replica.tick(now)
+        };
+
+        if !self.execute(effects) {
+            return;
+        }
+
+        loop {
+            let next_timer = self.timers.first().map(|at| self.started_at + Duration::from_millis(*at));
+            let effects = tokio::select! {
+                command = commands.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
+                    self.on_command(command)
+                }
+                _ = tokio::time::sleep_until(next_timer.unwrap_or_else(Instant::now)), if next_timer.is_some() => {
+                    let now = self.now();
+                    self.timers.retain(|at| *at > now);
+                    self.replica.tick(now)
+                }
+            };
+
+            if !self.execute(effects) {
+                break;
+            }
+        }
+
+        println!("replica {:?} stopped", self.replica.replica_number);
+    }
+
+    fn on_command(&mut self, command: Command) -> Vec<Effect<Vec<String>, String>> {
+        let now = self.now();
+        match command {
+            Command::Message(message) => self.replica.on_message(message, now),
+            Command::Request { mut request, reply } => {
+                if matches!(request.op, Operation::Register) {
+                    request.client_id |= REGISTER;
+                }
+                let key = (request.client_id, request.request_number);
+                self.pending_replies.insert(key, reply);
+                let effects = self.replica.on_message(Message::Request(request), now);
+                self.answer_errors(key, effects)
+            }
+            Command::Read { mut request, reply } => {
+                request.request_number |= READ;
+                let key = (request.client_id, request.request_number);
+                self.pending_replies.insert(key, reply);
+                let effects = self.replica.on_message(Message::Read(request), now);
+                self.answer_errors(key, effects)
+            }
+            Command::Connect { reply } => {
+                let _ = reply.send(ConnectData {
+                    configuration: self.addresses.clone(),
+                    current_view: self.replica.view_number as usize,
+                    epoch: self.replica.epoch as usize,
+                });
+                vec![]
+            }
+        }
+    }
+
+    /// Answers the request or read waiting under `key` with the error the replica turned it down with, if any. Errors
+    /// carry no request number, but the replica sends them in response to the message it turns down, except for a
+    /// read the state machine cannot serve, which `handle` never sends.
+    fn answer_errors(&mut self, key: (u64, usize), effects: Vec<Effect<Vec<String>, String>>) -> Vec<Effect<Vec<String>, String>> {
+        let (errors, effects) = effects.into_iter().partition::<Vec<_>, _>(|effect| {
+            matches!(effect, Effect::Reply { client_id, message: Message::Error { .. } } if *client_id == key.0)
+        });
+
+        if let Some(Effect::Reply { message, .. }) = errors.into_iter().next()
+            && let Some(reply) = self.pending_replies.remove(&key)
+        {
+            let _ = reply.send(message);
+        }
+        effects
+    }
+
+    /// Executes the effects of the replica. Returns false once the replica shut down.
+    fn execute(&mut self, effects: Vec<Effect<Vec<String>, String>>) -> bool {
+        for effect in effects {
+            match effect {
+                Effect::Send { to, message } => self.send(to, message),
+                Effect::Broadcast { to, message } => {
+                    for replica_number in to {
+                        self.send(replica_number, message.clone());
+                    }
+                }
+                Effect::SetTimer { at, .. } => {
+                    self.timers.insert(at);
+                }
+                // The replica ignores timers that fire after their deadline moved, so there is nothing to cancel.
+                Effect::CancelTimer { .. } => {}
+                Effect::ApplyCommited { op_number } => {
+                    println!("applied op_number: {:?}", op_number);
+                }
+                // Errors were answered along with the command they turn down.
+                Effect::Reply { client_id, message: Message::Reply { client_id: reply_client_id, view_number, request_id, result } } => {
+                    if let Some(reply) = self.pending_replies.remove(&(client_id, request_id)) {
+                        let request_id = request_id & !READ;
+                        let _ = reply.send(Message::Reply { client_id: reply_client_id, view_number, request_id, result });
+                    }
+                }
+                Effect::Reply { .. } => {}
+                Effect::Shutdown => return false,
+                // The replica stopped, and restarts from its data directory once the storage is fixed.
+                Effect::Failed { error } => {
+                    eprintln!("ERROR: the replica failed: {}", error);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        true
+    }
+
+    fn send(&mut self, to: u64, message: KvMessage) {
+        let Some(addr) = self.addresses.get(to as usize).cloned() else {
+            return;
+        };
+
+        let peer = self.peers.entry(to).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel(PEER_QUEUE_SIZE);
+            tokio::task::spawn(peer_connection(addr, rx));
+            tx
+        });
+
+        // The protocol recovers from lost messages, so a slow peer does not hold up the event loop. The drops are only
+        // logged at powers of two, which is enough to spot a peer that falls behind without flooding the log.
+        if let Err(err) = peer.try_send(message) {
+            self.dropped_messages += 1;
+            if self.dropped_messages.is_power_of_two() {
+                eprintln!("Dropped a message to replica {} ({} dropped so far): {}", to, self.dropped_messages, err);
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+}
+
+/// Accepts the connections of clients and peers, and forwards their requests to the replica event loop.
+pub async fn serve(listener: TcpListener, commands: mpsc::Sender<Command>) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {:?}", err);
+                continue;
+            }
+        };
+
+        let commands = commands.clone();
+        tokio::task::spawn(async move {
+            // Peers open their connections with the frame version byte, which cannot start an HTTP request.
+            let mut first = [0u8; 1];
+            if stream.peek(&mut first).await.is_ok_and(|n| n == 1) && first[0] == codec::VERSION {
+                receive_messages(stream, commands).await;
+                return;
+            }
+
+            let service = service_fn(move |req| handle(req, commands.clone()));
+            if let Err(err) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                eprintln!("Connection failed: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Forwards the messages of a peer connection to the replica event loop.
+async fn receive_messages(stream: TcpStream, commands: mpsc::Sender<Command>) {
+    let mut frames = FramedRead::new(stream, FrameCodec::<KvMessage>::new());
+    while let Some(frame) = frames.next().await {
+        match frame {
+            Ok(message) => {
+                if commands.send(Command::Message(message)).await.is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                eprintln!("Invalid frame from peer: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Sends the queued messages to a peer, reconnecting when the connection fails.
+async fn peer_connection(addr: String, mut messages: mpsc::Receiver<KvMessage>) {
+    while let Some(message) = messages.recv().await {
+        let stream = match TcpStream::connect(&addr).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to connect to {}: {:?}", addr, err);
+                continue;
+            }
+        };
+
+        let mut frames = FramedWrite::new(stream, FrameCodec::<KvMessage>::new());
+        let mut next = Some(message);
+        while let Some(message) = next {
+            if let Err(err) = frames.send(message).await {
+                eprintln!("Failed to send message to {}: {}", addr, err);
+                break;
+            }
+            next = messages.recv().await;
+        }
+    }
+}
+
+async fn handle(req: Request<Incoming>, commands: mpsc::Sender<Command>) -> Result<Response<Full<Bytes>>, Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/connect") => {
+            let (reply, rx) = oneshot::channel();
+            commands.send(Command::Connect { reply }).await?;
+            json_response(StatusCode::OK, &rx.await?)
+        }
+        (&Method::POST, "/register") => {
+            let body = req.into_body().collect().await?.aggregate();
+            let Ok(data) = serde_json::from_reader::<_, RegisterData>(body.reader()) else {
+                return error_response(StatusCode::BAD_REQUEST, "invalid request");
+            };
+
+            let (reply, rx) = oneshot::channel();
+            let request = ClientRequest { op: Operation::Register, client_id: data.nonce, request_number: 0, result: None };
+            commands.send(Command::Request { request, reply }).await?;
+            match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+                Ok(Ok(message)) => json_response(StatusCode::OK, &message),
+                _ => error_response(StatusCode::SERVICE_UNAVAILABLE, "no reply from the replica"),
+            }
+        }
+        (&Method::POST, "/") => {
+            let body = req.into_body().collect().await?.aggregate();
+            let Ok(data) = serde_json::from_reader::<_, RequestData>(body.reader()) else {
+                return error_response(StatusCode::BAD_REQUEST, "invalid request");
+            };
+
+            // GETs are answered by the primary without going through the log. Only well-formed ones are sent as reads,
+            // so that `KvStore::read` serves all of them and the replica never turns one down after the fact.
+            let (reply, rx) = oneshot::channel();
+            let client_id = data.client_id;
+            let request_number = data.request_number as usize;
+            let command = if matches!(data.op.as_slice(), [command, _] if command == "GET") {
+                Command::Read { request: ReadRequest { op: data.op, client_id, request_number }, reply }
+            } else {
+                let request = ClientRequest { op: Operation::Apply(data.op), client_id, request_number, result: None };
+                Command::Request { request, reply }
+            };
+
+            commands.send(command).await?;
+            match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+                Ok(Ok(message)) => json_response(StatusCode::OK, &message),
+                _ => error_response(StatusCode::SERVICE_UNAVAILABLE, "no reply from the replica"),
+            }
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Result<Response<Full<Bytes>>, Error> {
+    let response = Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(serde_json::to_string(body)?)))?;
+    Ok(response)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Result<Response<Full<Bytes>>, Error> {
+    json_response(status, &ErrorData { error: message })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::state::KvStore;
+
+    #[tokio::test]
+    async fn test_serve_dispatches_peers_and_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (commands, mut received) = mpsc::channel(16);
+        tokio::task::spawn(serve(listener, commands));
+
+        // A peer connection starts with the frame version byte, and carries replica messages.
+        let mut peer = FramedWrite::new(TcpStream::connect(address).await.unwrap(), FrameCodec::<KvMessage>::new());
+        peer.send(Message::Commit { op_number: 1, commit_number: 1, epoch: 0, view_number: 2 }).await.unwrap();
+        let Some(Command::Message(Message::Commit { view_number, .. })) = received.recv().await else {
+            panic!("expected the commit of the peer");
+        };
+        assert_eq!(view_number, 2);
+
+        // Anything else is an HTTP client.
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(b"POST /connect HTTP/1.1\r\nhost: test\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+        let Some(Command::Connect { reply }) = received.recv().await else {
+            panic!("expected the connect of the client");
+        };
+        reply.send(ConnectData { configuration: vec![address.to_string()], current_view: 0, epoch: 0 }).unwrap();
+
+        // The connection stays open, so read until the body is in.
+        let mut response = String::new();
+        let mut buf = [0; 1024];
+        while !response.contains('}') {
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed after {:?}", response);
+            response.push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\"current_view\":0"), "{}", response);
+    }
+
+    #[test]
+    fn test_replies_reach_the_request_read_or_registration_they_answer() {
+        // Without peer addresses the messages to the backups go nowhere, and their replies are made up below.
+        let replica = Replica::new(vec![0, 1, 2], 0, Rc::new(RefCell::new(KvStore::default())));
+        let mut server = Server::new(replica, vec![]);
+        let mut submit = |command: Command| {
+            let effects = server.on_command(command);
+            server.execute(effects);
+        };
+
+        // A request and a read with the same number, and a registration whose nonce is the same client id.
+        let (reply, mut request) = oneshot::channel();
+        let op = Operation::Apply(vec!["SET".to_string(), "a".to_string(), "1".to_string()]);
+        submit(Command::Request { request: ClientRequest { op, client_id: 5, request_number: 1, result: None }, reply });
+        let (reply, mut read) = oneshot::channel();
+        let op = vec!["GET".to_string(), "a".to_string()];
+        submit(Command::Read { request: ReadRequest { op, client_id: 5, request_number: 1 }, reply });
+        let (reply, mut register) = oneshot::channel();
+        submit(Command::Request { request: ClientRequest { op: Operation::Register, client_id: 5, request_number: 0, result: None }, reply });
+
+        let prepare_ok = Message::PrepareOk { epoch: 0, view_number: 0, replica_number: 1, op_number: 2, commit_number: 0 };
+        submit(Command::Message(prepare_ok));
+        submit(Command::Message(Message::HeartbeatOk { epoch: 0, view_number: 0, read_number: 1, replica_number: 1 }));
+
+        let Ok(Message::Reply { client_id: 5, request_id: 1, result: Some(result), .. }) = request.try_recv() else {
+            panic!("expected the reply to the request");
+        };
+        assert_eq!(result, "OK");
+        let Ok(Message::Reply { client_id: 5, request_id: 1, result: Some(result), .. }) = read.try_recv() else {
+            panic!("expected the reply to the read");
+        };
+        assert_eq!(result, "1");
+        assert!(matches!(register.try_recv(), Ok(Message::Reply { client_id: 2, request_id: 0, .. })));
+    }
+}
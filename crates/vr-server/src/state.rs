@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use vr_replica::state_machine::{Snapshot, StateMachine};
+
+/// The key-value store replicated by the server. Operations are the commands sent by `vr_proxy::Proxy`.
+#[derive(Debug, Default)]
+pub struct KvStore {
+    state: HashMap<String, String>,
+}
+
+impl StateMachine for KvStore {
+    type Input = Vec<String>;
+    type Output = String;
+
+    fn apply(&mut self, input: Self::Input) -> Self::Output {
+        let command = input.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+        match command.as_slice() {
+            ["SET", key, value] => {
+                self.state.insert(key.to_string(), value.to_string());
+                "OK".to_string()
+            }
+            ["GET", key] => self.state.get(*key).cloned().unwrap_or_else(|| "NULL".to_string()),
+            ["DEL", key] => {
+                self.state.remove(*key);
+                "OK".to_string()
+            }
+            _ => format!("ERROR: invalid command {:?}", input),
+        }
+    }
+
+    fn read(&self, input: &Self::Input) -> Option<Self::Output> {
+        match input.as_slice() {
+            [command, key] if command == "GET" => Some(self.state.get(key).cloned().unwrap_or_else(|| "NULL".to_string())),
+            _ => None,
+        }
+    }
+
+    fn input_size(&self, input: &Self::Input) -> usize {
+        input.iter().map(|s| s.len()).sum()
+    }
+
+    fn as_snapshot(&mut self) -> Option<&mut dyn Snapshot<Input = Self::Input, Output = Self::Output>> {
+        Some(self)
+    }
+}
+
+impl Snapshot for KvStore {
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.state).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> std::io::Result<()> {
+        self.state = serde_json::from_slice(snapshot)?;
+        Ok(())
+    }
+}
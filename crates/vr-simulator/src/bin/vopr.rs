@@ -0,0 +1,17 @@
+use vr_simulator::fuzzer;
+
+/// Fuzzes the replicas with random scenarios: `vopr [first seed] [seed count]`.
+fn main() {
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<u64>().expect("seeds are numbers"));
+    let first = args.next().unwrap_or(0);
+    let count = args.next().unwrap_or(1000);
+
+    match fuzzer::fuzz(first..first + count) {
+        Some((scenario, failure)) => {
+            eprintln!("seed {} failed: {:?}", scenario.seed, failure);
+            eprintln!("shrunk to: {:#?}", scenario);
+            std::process::exit(1);
+        }
+        None => println!("{} seeds passed", count),
+    }
+}
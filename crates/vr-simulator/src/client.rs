@@ -1,16 +1,21 @@
 use std::collections::HashMap;
 
-use vr_replica::{message::Message, state_machine::StateMachine};
+use serde::{Deserialize, Serialize};
+use vr_replica::{error::ReplicaError, message::Message, state_machine::StateMachine};
 
 use crate::{events::Event, simulator::NodeId};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Op {
     Set(String, u64),
     Get(String, Option<u64>),
     Del(String),
 }
 
+/// Set in the client id of a client without a session, which is also the nonce of its `Register`. Sessions are op
+/// numbers, which never reach the top bit, so a client id tells by itself whether it names a node or a session.
+pub const UNREGISTERED: u64 = 1 << 63;
+
 #[derive(Debug, Clone)]
 pub struct Client {
     pub id: NodeId,
@@ -18,12 +23,24 @@ pub struct Client {
 
     /// A sorted array containing the IP addresses of the replicas in the system.
     pub configuration: Vec<u64>,
-    /// The current view number. The primary replica is the one with the index `current_view` in the `configuration` array.
+    /// The current view number. The primary replica is the one with the index `current_view` modulo the size of the `configuration` array.
     pub current_view: u64,
-    /// The current request number. For future requests, it should ensure to be greater than the previous request number.
+    /// The number of the latest request. Every new request takes the next one, while a resent request keeps its own.
     pub request_number: u64,
     /// The current epoch number of the replica group.
     pub epoch: usize,
+
+    /// The request number still waiting for a reply, and how many times it was sent.
+    pub pending: Option<(u64, u32)>,
+    /// How long the client waits for a reply before resending a request.
+    pub timeout_ms: u64,
+    /// After this many sends without a reply, a request goes to every replica, as the client may not know the primary.
+    pub broadcast_after: u32,
+
+    /// The client id a `Register` got from the replica group. Requests go out under it instead of the node id.
+    pub session: Option<u64>,
+    /// The request number of the `Register` waiting for its reply.
+    pub registering: Option<u64>,
 }
 
 impl Client {
@@ -35,32 +52,101 @@ impl Client {
             current_view: 0, 
             request_number: 0, 
             epoch: 0, 
+            pending: None,
+            timeout_ms: 1000,
+            broadcast_after: 2,
+            session: None,
+            registering: None,
         }
     }
 
-    pub fn on_message<I: Clone + 'static>(&mut self, ev: Event<I>) -> () {
-        match ev {
-            Event::Msg(m) if matches!(m, Message::Reply { .. }) => {
-                let Message::Reply { result, .. } = m else {
-                    panic!("Unexpected message");
-                };
+    /// The primary of the view the client believes current.
+    pub fn primary(&self) -> u64 {
+        self.configuration[self.current_view as usize % self.configuration.len()]
+    }
+
+    /// The client id its requests carry.
+    pub fn client_id(&self) -> u64 {
+        self.session.unwrap_or(self.nonce())
+    }
+
+    /// The nonce its `Register` carries, which routes the reply back until the client has a session.
+    pub fn nonce(&self) -> u64 {
+        self.id.0 | UNREGISTERED
+    }
+
+    /// Takes the next request number, and waits for its reply.
+    pub fn next_request(&mut self) -> u64 {
+        self.request_number += 1;
+        self.pending = Some((self.request_number, 1));
+        self.request_number
+    }
+
+    /// Counts another send of `request_number` after a timeout. Returns the replicas to send it to, or nothing if the
+    /// request was answered or superseded in the meantime.
+    pub fn on_timeout(&mut self, request_number: u64) -> Option<Vec<u64>> {
+        let (_, attempts) = self.pending.as_mut().filter(|(pending, _)| *pending == request_number)?;
+        *attempts += 1;
+        if *attempts > self.broadcast_after {
+            return Some(self.configuration.clone());
+        }
+        Some(vec![self.primary()])
+    }
 
-                // TODO: Not sure if we should update the request_number only on reply.
-                self.request_number += 1;
+    pub fn on_message<I: Clone + 'static>(&mut self, ev: Event<I>) {
+        match ev {
+            Event::Msg(Message::Reply { client_id, view_number, request_id, result }) => {
+                // Replies come from the primary, so they tell which view is current.
+                self.current_view = self.current_view.max(view_number);
+                if self.pending.is_some_and(|(pending, _)| pending == request_id as u64) {
+                    self.pending = None;
+                }
+                if self.registering == Some(request_id as u64) {
+                    self.session = Some(client_id);
+                    self.registering = None;
+                }
                 if let Some(op) = result {
                     self.apply_op(op);
                 }
             },
-            _ => panic!("Unexpected message"),
+            Event::Msg(Message::Error { error }) => self.on_error(error),
+            // Nothing else is meant for clients, so it is dropped.
+            _ => {},
+        }
+    }
+
+    /// Follows the primary a backup points to, and gives up a request that can never succeed. A replica that is
+    /// not serving its view, or is reconfiguring, is retried after the timeout.
+    fn on_error(&mut self, error: ReplicaError) {
+        match error {
+            ReplicaError::NotPrimary { view_number, .. } => {
+                self.current_view = self.current_view.max(view_number);
+            },
+            // The session was evicted, so the client has to register again.
+            ReplicaError::UnknownSession { .. } => {
+                self.session = None;
+                self.pending = None;
+            },
+            ReplicaError::StaleRequest { request_number, .. } => {
+                if self.pending.is_some_and(|(pending, _)| pending == request_number as u64) {
+                    self.pending = None;
+                }
+            },
+            ReplicaError::WrongEpoch { .. } | ReplicaError::EmptyConfiguration | ReplicaError::NotReadOnly => {
+                self.pending = None;
+            },
+            ReplicaError::WrongView { .. } | ReplicaError::ReconfigurationPending => {},
         }
     }
 
-    fn apply_op(&mut self, op: Op) -> () {
+    fn apply_op(&mut self, op: Op) {
         match op {
-            Op::Set(key, value) => {
+            Op::Set(key, value) | Op::Get(key, Some(value)) => {
                 self.state.insert(key, value);
             },
-            _ => todo!()
+            Op::Get(key, None) | Op::Del(key) => {
+                self.state.remove(&key);
+            },
         }
     }
 }
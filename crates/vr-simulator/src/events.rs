@@ -1,8 +1,11 @@
 use vr_replica::{clock::TimerKind, message::Message};
 
+use serde::{Deserialize, Serialize};
+
 use crate::client::Op;
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event<Input: Clone> {
-    Msg(Message<Input, Op>),        
+    Msg(Message<Input, Op>),
     TimerFired(TimerKind),
 }
@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use vr_replica::replica::Replica;
+use vr_replica::storage::{DurableState, FsyncPolicy, MemoryLogStorage};
+
+use crate::client::{Client, Op};
+use crate::history::{NotLinearizable, check_linearizable};
+use crate::invariants::Violation;
+use crate::kv::KvStore;
+use crate::rng::Rng;
+use crate::simulator::{CrashFaults, Link, NodeId, NodeKind, RestartMode, Simulator, SimulatorConfig};
+use crate::workload::{Generator, Workload};
+
+/// How long a scenario runs after its last scheduled op or fault, for the cluster to settle.
+const SETTLE_MS: u64 = 20_000;
+
+/// A client operation of a scenario. Every op is sent by its own client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledOp {
+    pub at: u64,
+    /// The view the client believes current, whose primary it sends the op to.
+    pub view: u64,
+    pub op: Op,
+    /// Sent as a read, which skips the log.
+    pub read: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    Crash { replica: u64, at: u64, restart_at: u64, mode: RestartMode },
+    Partition { groups: Vec<Vec<u64>>, at: u64, heal_at: u64 },
+}
+
+/// Everything a fuzzer run depends on besides the seed, so that it can be shrunk and replayed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub seed: u64,
+    pub replica_count: u64,
+    pub link: Link,
+    pub workload: Vec<ScheduledOp>,
+    pub faults: Vec<Fault>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Failure {
+    Invariant(Violation),
+    NotLinearizable(NotLinearizable),
+    Panic(String),
+}
+
+impl Scenario {
+    /// A random cluster, workload and fault schedule. At most `f` replicas ever crash, so a quorum always survives.
+    pub fn generate(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let replica_count = if rng.chance(50) { 3 } else { 5 };
+        let link = Link {
+            up: true,
+            base_ms: 50 + rng.up_to(100),
+            jitter_ms: rng.up_to(50),
+            drop_pct: rng.up_to(5) as u8,
+            dup_pct: rng.up_to(5) as u8,
+        };
+
+        let mut generator = Generator::new(Workload { keys: 3, get_pct: 50, del_pct: 15, ..Default::default() }, seed);
+        let mut workload = (0..5 + rng.up_to(25))
+            .map(|_| {
+                let next = generator.next_op();
+                let read = matches!(next.op, Op::Get(..)) && rng.chance(50);
+                ScheduledOp { at: rng.up_to(20_000), view: rng.up_to(replica_count - 1), op: next.op, read }
+            })
+            .collect::<Vec<_>>();
+        workload.sort_by_key(|op| op.at);
+
+        // Rolls the crashes as `SimulatorConfig::crash_faults` would, but up front, so that shrinking can drop them.
+        let crash_faults = CrashFaults {
+            interval_ms: 4000,
+            crash_pct: 10,
+            max_crashed: (replica_count as usize - 1) / 2,
+            down_ms: 1000,
+            amnesia_pct: 50,
+        };
+        let mut faults = Vec::new();
+        let mut crashed = Vec::new();
+        for at in (crash_faults.interval_ms..20_000).step_by(crash_faults.interval_ms as usize) {
+            for replica in 0..replica_count {
+                if crashed.len() >= crash_faults.max_crashed || crashed.contains(&replica) || !rng.chance(crash_faults.crash_pct) {
+                    continue;
+                }
+
+                crashed.push(replica);
+                let mode = if rng.chance(crash_faults.amnesia_pct) { RestartMode::Amnesia } else { RestartMode::Durable };
+                let restart_at = at + crash_faults.down_ms + rng.up_to(crash_faults.down_ms);
+                faults.push(Fault::Crash { replica, at, restart_at, mode });
+            }
+        }
+
+        for _ in 0..rng.up_to(2) {
+            let at = rng.up_to(20_000);
+            let (left, right) = (0..replica_count).partition(|_| rng.chance(50));
+            faults.push(Fault::Partition { groups: vec![left, right], at, heal_at: at + 1000 + rng.up_to(8000) });
+        }
+
+        Self { seed, replica_count, link, workload, faults }
+    }
+
+    fn end(&self) -> u64 {
+        let last_op = self.workload.iter().map(|op| op.at);
+        let last_fault = self.faults.iter().map(|fault| match fault {
+            Fault::Crash { restart_at, .. } => *restart_at,
+            Fault::Partition { heal_at, .. } => *heal_at,
+        });
+        last_op.chain(last_fault).max().unwrap_or(0) + SETTLE_MS
+    }
+}
+
+/// Runs the scenario, and returns the first invariant violation, non-linearizable history or panic.
+pub fn run_scenario(scenario: &Scenario) -> Result<(), Failure> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut sim = setup(scenario);
+        for (i, op) in scenario.workload.iter().enumerate() {
+            sim.run_until(op.at);
+            let client_id = NodeId(i as u64);
+            if op.read {
+                sim.start_client_read(client_id, op.op.clone());
+            } else {
+                sim.start_client_request(client_id, op.op.clone());
+            }
+        }
+        sim.run_until(scenario.end());
+
+        if let Some(violation) = sim.violation() {
+            return Err(Failure::Invariant(violation.clone()));
+        }
+        check_linearizable(sim.history()).map_err(Failure::NotLinearizable)
+    }));
+
+    result.unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<String>().cloned().or_else(|| panic.downcast_ref::<&str>().map(|m| m.to_string()));
+        Err(Failure::Panic(message.unwrap_or_default()))
+    })
+}
+
+fn setup(scenario: &Scenario) -> Simulator<Op> {
+    let config = SimulatorConfig { seed: scenario.seed, record_violations: true, ..Default::default() };
+    let mut sim = Simulator::new(Some(config));
+    let configuration = (0..scenario.replica_count).collect::<Vec<_>>();
+    for id in &configuration {
+        sim.add_replica(NodeId(*id), Replica::new(configuration.clone(), *id, Rc::new(RefCell::new(KvStore::default()))));
+        for other in configuration.iter().filter(|other| *other > id) {
+            sim.set_link(NodeKind::Replica(NodeId(*id)), NodeKind::Replica(NodeId(*other)), scenario.link.clone());
+        }
+    }
+
+    let factory_configuration = configuration.clone();
+    sim.set_replica_factory(move |id, state: DurableState<Op, Op>| {
+        let storage = Rc::new(RefCell::new(MemoryLogStorage::with_state(FsyncPolicy::PerOp, state)));
+        let state_machine = Rc::new(RefCell::new(KvStore::default()));
+        Replica::with_storage(factory_configuration.clone(), id.0, state_machine, storage).unwrap()
+    });
+
+    for (i, op) in scenario.workload.iter().enumerate() {
+        let i = i as u64;
+        let mut client = Client::new(NodeId(i), configuration.clone());
+        client.current_view = op.view;
+        sim.add_client(NodeId(i), client);
+        for replica in &configuration {
+            sim.set_link(NodeKind::Client(NodeId(i)), NodeKind::Replica(NodeId(*replica)), scenario.link.clone());
+        }
+    }
+
+    for fault in &scenario.faults {
+        match fault {
+            Fault::Crash { replica, at, restart_at, mode } => {
+                sim.crash(NodeId(*replica), *at);
+                sim.restart(NodeId(*replica), *mode, *restart_at);
+            }
+            Fault::Partition { groups, at, heal_at } => {
+                let groups = groups.iter().map(|g| g.iter().map(|id| NodeKind::Replica(NodeId(*id))).collect()).collect();
+                sim.partition(groups, *at);
+                sim.heal(*heal_at);
+            }
+        }
+    }
+
+    sim
+}
+
+/// Runs every seed in `seeds`, and stops at the first failure, with the scenario shrunk to a minimal reproducer.
+pub fn fuzz(seeds: impl IntoIterator<Item = u64>) -> Option<(Scenario, Failure)> {
+    for seed in seeds {
+        let scenario = Scenario::generate(seed);
+        if let Err(failure) = run_scenario(&scenario) {
+            let scenario = shrink(scenario, |s| run_scenario(s).is_err());
+            let failure = run_scenario(&scenario).err().unwrap_or(failure);
+            return Some((scenario, failure));
+        }
+    }
+
+    None
+}
+
+/// Removes faults and ops, and quiets the links, for as long as `still_fails` holds.
+pub fn shrink(mut scenario: Scenario, mut still_fails: impl FnMut(&Scenario) -> bool) -> Scenario {
+    loop {
+        let mut shrunk = false;
+        for i in (0..scenario.faults.len()).rev() {
+            let mut candidate = scenario.clone();
+            candidate.faults.remove(i);
+            if still_fails(&candidate) {
+                scenario = candidate;
+                shrunk = true;
+            }
+        }
+
+        for i in (0..scenario.workload.len()).rev() {
+            let mut candidate = scenario.clone();
+            candidate.workload.remove(i);
+            if still_fails(&candidate) {
+                scenario = candidate;
+                shrunk = true;
+            }
+        }
+
+        let quiet: [fn(&mut Link); 3] = [|l| l.drop_pct = 0, |l| l.dup_pct = 0, |l| l.jitter_ms = 0];
+        for quiet in quiet {
+            let mut candidate = scenario.clone();
+            quiet(&mut candidate.link);
+            if candidate.link != scenario.link && still_fails(&candidate) {
+                scenario = candidate;
+                shrunk = true;
+            }
+        }
+
+        if !shrunk {
+            return scenario;
+        }
+    }
+}
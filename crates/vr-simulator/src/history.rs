@@ -0,0 +1,184 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::client::Op;
+use crate::simulator::NodeId;
+
+/// A client operation, from when the client sent it to when it got the reply, in simulated time.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry<Input> {
+    pub client_id: NodeId,
+    pub request_number: u64,
+    pub op: Input,
+    pub invoked_at: u64,
+    /// When the reply arrived and what it said. An operation without one may or may not have taken effect.
+    pub response: Option<(u64, Op)>,
+}
+
+/// Every operation the clients of a simulation invoked, in invocation order.
+#[derive(Debug, Clone)]
+pub struct History<Input> {
+    pub entries: Vec<HistoryEntry<Input>>,
+}
+
+impl<Input> Default for History<Input> {
+    fn default() -> Self {
+        Self { entries: Vec::new() }
+    }
+}
+
+impl<Input> History<Input> {
+    pub fn invoke(&mut self, client_id: NodeId, request_number: u64, op: Input, now: u64) {
+        self.entries.push(HistoryEntry { client_id, request_number, op, invoked_at: now, response: None });
+    }
+
+    /// Completes the oldest pending operation of the client with this request number. Replies to operations that
+    /// already completed, e.g. duplicates, are ignored.
+    pub fn complete(&mut self, client_id: NodeId, request_number: u64, output: Op, now: u64) {
+        let pending = self.entries.iter_mut().find(|e| {
+            e.client_id == client_id && e.request_number == request_number && e.response.is_none()
+        });
+
+        if let Some(entry) = pending {
+            entry.response = Some((now, output));
+        }
+    }
+}
+
+/// The operations on a key that no order consistent with real time explains.
+#[derive(Debug, Clone)]
+pub struct NotLinearizable {
+    pub key: String,
+    pub entries: Vec<HistoryEntry<Op>>,
+}
+
+/// Checks that the history is linearizable against a sequential key-value store, following Wing and Gong.
+///
+/// Operations on different keys never constrain each other, so every key is checked on its own.
+pub fn check_linearizable(history: &History<Op>) -> Result<(), NotLinearizable> {
+    let mut by_key = BTreeMap::<&str, Vec<HistoryEntry<Op>>>::new();
+    for entry in &history.entries {
+        by_key.entry(key_of(&entry.op)).or_default().push(entry.clone());
+    }
+
+    for (key, entries) in by_key {
+        let mut search = Search { entries: &entries, visited: HashSet::new() };
+        if !search.linearize(&mut vec![false; entries.len()], None) {
+            return Err(NotLinearizable { key: key.to_string(), entries });
+        }
+    }
+
+    Ok(())
+}
+
+fn key_of(op: &Op) -> &str {
+    match op {
+        Op::Set(key, _) | Op::Get(key, _) | Op::Del(key) => key,
+    }
+}
+
+/// Applies `op` to the value of its key, and returns what a sequential store would have replied.
+fn apply(op: &Op, value: &mut Option<u64>) -> Op {
+    match op {
+        Op::Set(key, v) => {
+            *value = Some(*v);
+            Op::Set(key.clone(), *v)
+        }
+        Op::Get(key, _) => Op::Get(key.clone(), *value),
+        Op::Del(key) => {
+            *value = None;
+            Op::Del(key.clone())
+        }
+    }
+}
+
+struct Search<'a> {
+    entries: &'a [HistoryEntry<Op>],
+    /// The sets of linearized operations and resulting values already known to lead nowhere.
+    visited: HashSet<(Vec<bool>, Option<u64>)>,
+}
+
+impl Search<'_> {
+    fn linearize(&mut self, done: &mut Vec<bool>, value: Option<u64>) -> bool {
+        let remaining = self.entries.iter().zip(done.iter()).filter(|(_, done)| !**done).map(|(e, _)| e);
+        // Pending operations may never have taken effect, so only the completed ones must be linearized.
+        let Some(deadline) = remaining.filter_map(|e| e.response.as_ref().map(|(at, _)| *at)).min() else {
+            return true;
+        };
+
+        if !self.visited.insert((done.clone(), value)) {
+            return false;
+        }
+
+        for i in 0..self.entries.len() {
+            // An operation can go next only if no remaining one returned before it was invoked.
+            let entry = &self.entries[i];
+            if done[i] || entry.invoked_at > deadline {
+                continue;
+            }
+
+            let mut next = value;
+            let output = apply(&entry.op, &mut next);
+            if entry.response.as_ref().is_some_and(|(_, response)| *response != output) {
+                continue;
+            }
+
+            done[i] = true;
+            if self.linearize(done, next) {
+                return true;
+            }
+            done[i] = false;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(history: &mut History<Op>, client: u64, value: u64, invoked_at: u64, returned_at: Option<u64>) {
+        history.invoke(NodeId(client), 0, Op::Set("a".to_string(), value), invoked_at);
+        if let Some(at) = returned_at {
+            history.complete(NodeId(client), 0, Op::Set("a".to_string(), value), at);
+        }
+    }
+
+    fn get(history: &mut History<Op>, client: u64, value: Option<u64>, invoked_at: u64, returned_at: u64) {
+        history.invoke(NodeId(client), 0, Op::Get("a".to_string(), None), invoked_at);
+        history.complete(NodeId(client), 0, Op::Get("a".to_string(), value), returned_at);
+    }
+
+    #[test]
+    fn test_concurrent_writes_may_take_effect_in_either_order() {
+        let mut history = History::default();
+        set(&mut history, 0, 1, 0, Some(10));
+        set(&mut history, 1, 2, 5, Some(15));
+        get(&mut history, 2, Some(1), 20, 30);
+        assert!(check_linearizable(&history).is_ok());
+    }
+
+    #[test]
+    fn test_stale_read_is_not_linearizable() {
+        let mut history = History::default();
+        set(&mut history, 0, 1, 0, Some(10));
+        set(&mut history, 1, 2, 20, Some(30));
+        get(&mut history, 2, Some(1), 40, 50);
+
+        let err = check_linearizable(&history).unwrap_err();
+        assert_eq!(err.key, "a");
+        assert_eq!(err.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_pending_write_may_or_may_not_take_effect() {
+        let mut history = History::default();
+        set(&mut history, 0, 1, 0, None);
+        get(&mut history, 1, None, 10, 20);
+        get(&mut history, 2, Some(1), 30, 40);
+        assert!(check_linearizable(&history).is_ok());
+
+        get(&mut history, 3, None, 50, 60);
+        assert!(check_linearizable(&history).is_err());
+    }
+}
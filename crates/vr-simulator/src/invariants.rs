@@ -0,0 +1,157 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+
+use vr_replica::message::{Checkpoint, ClientRequest};
+use vr_replica::replica::{Replica, Status};
+use vr_replica::types::OpNumber;
+
+use crate::client::Op;
+use crate::simulator::NodeId;
+
+/// A safety property the simulator checks against every live replica after each step.
+///
+/// Invariants may remember what they saw in earlier steps. A replica missing from a step crashed, and may come back
+/// with less state than it had.
+pub trait Invariant<Input: Clone + Debug + 'static> {
+    fn name(&self) -> &str;
+
+    fn check(&mut self, replicas: &[(NodeId, &Replica<Input, Op>)]) -> Result<(), String>;
+}
+
+/// The invariant that failed, and where to find it again.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub seed: u64,
+    pub step: u64,
+    pub invariant: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invariant `{}` violated at step {} with seed {}: {}", self.invariant, self.step, self.seed, self.message)
+    }
+}
+
+/// The invariants every simulation checks unless told otherwise.
+pub fn default_invariants<Input: Clone + Debug + 'static>() -> Vec<Box<dyn Invariant<Input>>> {
+    vec![
+        Box::new(NoDivergentCommits::default()),
+        Box::new(CommitNumberNeverDecreases::default()),
+        Box::new(OnePrimaryPerView::default()),
+    ]
+}
+
+/// What identifies a request in a log entry, as `ClientRequest` is not comparable.
+fn request_id<Input: Clone + Debug + 'static>(request: &ClientRequest<Input, Op>) -> String {
+    format!("{}/{}/{:?}", request.client_id, request.request_number, request.op)
+}
+
+/// The committed entries a replica still holds in its log.
+fn committed<Input: Clone + Debug + 'static>(replica: &Replica<Input, Op>) -> impl Iterator<Item = &(OpNumber, ClientRequest<Input, Op>)> {
+    replica.log.iter().take_while(|(op_number, _)| *op_number <= replica.commit_number)
+}
+
+/// What identifies the state a checkpoint holds, i.e. its snapshot and client table.
+fn checkpoint_digest(checkpoint: &Checkpoint<Op>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    checkpoint.data.hash(&mut hasher);
+    format!("{:?}", checkpoint.client_table).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// No two replicas ever commit different requests at the same op number, even if one of them compacted it away since,
+/// nor take checkpoints of different states at the same op number.
+#[derive(Default)]
+pub struct NoDivergentCommits {
+    committed: HashMap<OpNumber, (NodeId, String)>,
+    checkpoints: HashMap<OpNumber, (NodeId, u64)>,
+}
+
+impl<Input: Clone + Debug + 'static> Invariant<Input> for NoDivergentCommits {
+    fn name(&self) -> &str {
+        "no divergent commits"
+    }
+
+    fn check(&mut self, replicas: &[(NodeId, &Replica<Input, Op>)]) -> Result<(), String> {
+        for (id, replica) in replicas {
+            for (op_number, request) in committed(replica) {
+                let request = request_id(request);
+                let (first, seen) = self.committed.entry(*op_number).or_insert((*id, request.clone()));
+                if *seen != request {
+                    return Err(format!("op {} committed as {} by {:?} and as {} by {:?}", op_number, seen, first, request, id));
+                }
+            }
+
+            if let Some(checkpoint) = &replica.checkpoint {
+                let digest = checkpoint_digest(checkpoint);
+                let (first, seen) = self.checkpoints.entry(checkpoint.op_number).or_insert((*id, digest));
+                if *seen != digest {
+                    return Err(format!("{:?} and {:?} checkpointed different states at op {}", first, id, checkpoint.op_number));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A replica never forgets that an op was committed, unless it crashed or is recovering after losing its state.
+#[derive(Default)]
+pub struct CommitNumberNeverDecreases {
+    commit_numbers: BTreeMap<NodeId, usize>,
+}
+
+impl<Input: Clone + Debug + 'static> Invariant<Input> for CommitNumberNeverDecreases {
+    fn name(&self) -> &str {
+        "commit number never decreases"
+    }
+
+    fn check(&mut self, replicas: &[(NodeId, &Replica<Input, Op>)]) -> Result<(), String> {
+        let previous = std::mem::take(&mut self.commit_numbers);
+        for (id, replica) in replicas {
+            if replica.status == Status::Recovering {
+                continue;
+            }
+
+            if let Some(commit_number) = previous.get(id)
+                && replica.commit_number < *commit_number
+            {
+                return Err(format!("{:?} went from commit number {} to {}", id, commit_number, replica.commit_number));
+            }
+            self.commit_numbers.insert(*id, replica.commit_number);
+        }
+
+        Ok(())
+    }
+}
+
+/// At most one replica acts as the primary of a view in `Status::Normal`, over the whole run.
+#[derive(Default)]
+pub struct OnePrimaryPerView {
+    primaries: HashMap<(u64, u64), NodeId>,
+}
+
+impl<Input: Clone + Debug + 'static> Invariant<Input> for OnePrimaryPerView {
+    fn name(&self) -> &str {
+        "one primary per view"
+    }
+
+    fn check(&mut self, replicas: &[(NodeId, &Replica<Input, Op>)]) -> Result<(), String> {
+        for (id, replica) in replicas {
+            if replica.status != Status::Normal || !replica.is_primary() {
+                continue;
+            }
+
+            let view = (replica.epoch, replica.view_number);
+            let primary = self.primaries.entry(view).or_insert(*id);
+            if primary != id {
+                return Err(format!("both {:?} and {:?} are primary of view {} in epoch {}", primary, id, view.1, view.0));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use vr_replica::state_machine::{Snapshot, StateMachine};
+
+use crate::client::Op;
+
+/// The key-value store the simulated replicas run over `client::Op`. Its snapshot is the sorted `key=value` lines, so
+/// replicas in the same state take the same snapshot.
+#[derive(Debug, Default)]
+pub struct KvStore {
+    pub state: BTreeMap<String, u64>,
+}
+
+impl StateMachine for KvStore {
+    type Input = Op;
+    type Output = Op;
+
+    fn apply(&mut self, input: Self::Input) -> Self::Output {
+        match input {
+            Op::Set(key, value) => {
+                self.state.insert(key.clone(), value);
+                Op::Set(key, value)
+            }
+            Op::Get(key, _) => {
+                let value = self.state.get(&key).cloned();
+                Op::Get(key, value)
+            }
+            Op::Del(key) => {
+                self.state.remove(&key);
+                Op::Del(key)
+            }
+        }
+    }
+
+    fn read(&self, input: &Self::Input) -> Option<Self::Output> {
+        match input {
+            Op::Get(key, _) => Some(Op::Get(key.clone(), self.state.get(key).cloned())),
+            _ => None,
+        }
+    }
+
+    fn as_snapshot(&mut self) -> Option<&mut dyn Snapshot<Input = Self::Input, Output = Self::Output>> {
+        Some(self)
+    }
+}
+
+impl Snapshot for KvStore {
+    fn snapshot(&self) -> Vec<u8> {
+        let entries = self.state.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>();
+        entries.join("\n").into_bytes()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> std::io::Result<()> {
+        let invalid = |line: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid snapshot line: {:?}", line));
+        self.state = String::from_utf8_lossy(snapshot)
+            .lines()
+            .map(|line| {
+                let (key, value) = line.split_once('=').ok_or_else(|| invalid(line))?;
+                Ok((key.to_string(), value.parse().map_err(|_| invalid(line))?))
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(())
+    }
+}
@@ -1,18 +1,37 @@
-mod events;
-mod simulator;
-mod client;
+pub mod events;
+pub mod fuzzer;
+pub mod history;
+pub mod invariants;
+pub mod kv;
+pub mod report;
+pub mod simulator;
+pub mod client;
+pub mod rng;
+pub mod trace;
+pub mod workload;
 
 #[cfg(test)]
 mod tests {
     use std::cell::RefCell;
-    use std::collections::HashMap;
     use std::rc::Rc;
 
-    use vr_replica::replica::Replica;
+    use vr_replica::clock::TimerKind;
+    use vr_replica::effect::Effect;
+    use vr_replica::error::ReplicaError;
+    use vr_replica::message::{Checkpoint, ClientRequest, Message, Operation};
+    use vr_replica::replica::{Batching, ReadMode, Replica, Status};
     use vr_replica::state_machine::StateMachine;
+    use vr_replica::storage::{DurableState, FsyncPolicy, LogStorage, MemoryLogStorage, Metadata};
 
     use crate::client::{Client, Op};
-    use crate::simulator::{Link, NodeId, NodeKind, Simulator, SimulatorConfig};
+    use crate::events::Event;
+    use crate::fuzzer::{self, Fault, Scenario, ScheduledOp};
+    use crate::history::check_linearizable;
+    use crate::invariants::{Invariant, NoDivergentCommits};
+    use crate::kv::KvStore;
+    use crate::trace::ReplayError;
+    use crate::simulator::{CrashFaults, Link, NodeId, NodeKind, RestartMode, Simulator, SimulatorConfig};
+    use crate::workload::{Arrival, KeyDistribution, Workload};
 
     #[test]
     fn test_setup_clients_and_replicas() {
@@ -43,12 +62,12 @@ mod tests {
         for replica in replicas.clone() {
             let node_id = NodeId(replica.replica_number);
             let other_replicas = replicas.iter().filter(|r| r.replica_number != replica.replica_number);
-            let has_link_to_other_replicas = other_replicas.clone().all(|r| links.0.get(&(NodeKind::Replica(node_id), NodeKind::Replica(NodeId(r.replica_number)))).is_some());
+            let has_link_to_other_replicas = other_replicas.clone().all(|r| links.0.contains_key(&(NodeKind::Replica(node_id), NodeKind::Replica(NodeId(r.replica_number)))));
             if has_link_to_other_replicas {
                 replica_links += 1;
             }
 
-            let has_link_from_other_replicas = other_replicas.clone().all(|r| links.0.get(&(NodeKind::Replica(NodeId(r.replica_number)), NodeKind::Replica(node_id))).is_some());
+            let has_link_from_other_replicas = other_replicas.clone().all(|r| links.0.contains_key(&(NodeKind::Replica(NodeId(r.replica_number)), NodeKind::Replica(node_id))));
             if has_link_from_other_replicas {
                 replica_links += 1;
             }
@@ -77,13 +96,1083 @@ mod tests {
         println!("finished")
     }
 
+    #[test]
+    fn test_view_change_after_primary_failure() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 1, 3);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(2000);
+
+        let down = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: false };
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)), down.clone());
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), down);
+        sim.run_until(10000);
+
+        let replicas = sim.get_replicas();
+        for replica in replicas.iter().filter(|r| r.replica_number != 0) {
+            assert_eq!(replica.view_number, 1);
+            assert_eq!(replica.status, Status::Normal);
+            assert_eq!(replica.op_number, 1);
+            assert_eq!(replica.commit_number, 1);
+        }
+    }
+
+    #[test]
+    fn test_recovery_after_replica_restart() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 1, 3);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(2000);
+
+        sim.add_replica(NodeId(2), setup_replica(2, vec![0, 1, 2]));
+        assert!(sim.recover_replica(NodeId(2), 42));
+        sim.run_until(4000);
+
+        let replicas = sim.get_replicas();
+        let replica = replicas.iter().find(|r| r.replica_number == 2).unwrap();
+        assert_eq!(replica.status, Status::Normal);
+        assert_eq!(replica.log.len(), 1);
+        assert_eq!(replica.op_number, 1);
+        assert_eq!(replica.commit_number, 1);
+    }
+
+    #[test]
+    fn test_state_transfer_to_lagging_backup() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 2, 3);
+
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        let down = Link { up: false, ..link.clone() };
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), down);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(500);
+
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), link);
+        sim.start_client_request(clients[1].id, Op::Set("b".to_string(), 2));
+        sim.run_until(3000);
+
+        let replicas = sim.get_replicas();
+        let replica = replicas.iter().find(|r| r.replica_number == 2).unwrap();
+        assert_eq!(replica.op_number, 2);
+        assert_eq!(replica.commit_number, 2);
+        assert_eq!(replica.log.len(), 2);
+    }
+
+    #[test]
+    fn test_reconfiguration_grows_the_replica_group() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 2, 3);
+        add_joining_replicas(&mut sim, &[3, 4], 5);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(1000);
+        sim.start_client_reconfiguration(clients[1].id, 0, vec![0, 1, 2, 3, 4]);
+        sim.run_until(10000);
+
+        let replicas = sim.get_replicas();
+        assert_eq!(replicas.len(), 5);
+        for replica in replicas {
+            assert_eq!(replica.epoch, 1);
+            assert_eq!(replica.status, Status::Normal);
+            assert_eq!(replica.op_number, 2);
+            assert_eq!(replica.commit_number, 2);
+        }
+    }
+
+    #[test]
+    fn test_reconfiguration_replaces_a_replica() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 2, 3);
+        add_joining_replicas(&mut sim, &[3], 4);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(1000);
+        sim.start_client_reconfiguration(clients[1].id, 0, vec![0, 1, 3]);
+        sim.run_until(10000);
+
+        let replicas = sim.get_replicas();
+        let mut replica_numbers = replicas.iter().map(|r| r.replica_number).collect::<Vec<_>>();
+        replica_numbers.sort();
+        assert_eq!(replica_numbers, vec![0, 1, 3]);
+        for replica in replicas {
+            assert_eq!(replica.epoch, 1);
+            assert_eq!(replica.status, Status::Normal);
+            assert_eq!(replica.commit_number, 2);
+        }
+    }
+
+    #[test]
+    fn test_batched_fsync_delays_commit_until_durable() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 1, 3, link, |replica| {
+            replica.storage = Rc::new(RefCell::new(MemoryLogStorage::new(FsyncPolicy::Batched { max_ops: 10, max_delay_ms: 50 })));
+        });
+
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+        // Backups have the prepare, but acknowledge it only after their sync timer fires.
+        sim.run_until(300);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.log.len(), 1);
+            assert_eq!(replica.commit_number, 0);
+        }
+
+        sim.run_until(1000);
+        let primary = sim.get_replicas().into_iter().find(|r| r.replica_number == 0).unwrap();
+        assert_eq!(primary.commit_number, 1);
+
+        // A replica restarted from its storage gets the durable log back, and rejoins through a view change.
+        let backup = sim.get_replicas().into_iter().find(|r| r.replica_number == 1).unwrap();
+        assert_eq!(backup.storage.borrow().durable_op_number(), 1);
+        let state = Rc::new(RefCell::new(KvStore::default()));
+        let restarted = Replica::with_storage(vec![0, 1, 2], 1, state, backup.storage.clone()).unwrap();
+        assert_eq!(restarted.status, Status::ViewChange);
+        assert_eq!(restarted.log.len(), 1);
+    }
+
+    #[test]
+    fn test_replica_stops_when_its_storage_fails() {
+        #[derive(Debug)]
+        struct BrokenStorage;
+
+        impl LogStorage<Op, Op> for BrokenStorage {
+            fn append(&mut self, _: usize, _: &ClientRequest<Op, Op>) -> std::io::Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn truncate(&mut self, _: usize) -> std::io::Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn save_metadata(&mut self, _: &Metadata) -> std::io::Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn save_checkpoint(&mut self, _: &Checkpoint<Op>) -> std::io::Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn sync(&mut self) -> std::io::Result<()> {
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn durable_op_number(&self) -> usize {
+                0
+            }
+
+            fn sync_delay(&self) -> Option<u64> {
+                None
+            }
+
+            fn load(&mut self) -> std::io::Result<DurableState<Op, Op>> {
+                Ok(DurableState::default())
+            }
+        }
+
+        // The backup cannot make the entry durable, so it stops instead of acknowledging it.
+        let mut backup = setup_replica(1, vec![0, 1, 2]);
+        backup.storage = Rc::new(RefCell::new(BrokenStorage));
+        let request = ClientRequest { op: Operation::Apply(Op::Set("a".to_string(), 1)), client_id: 0, request_number: 1, result: None };
+        let prepare = Message::Prepare { epoch: 0, view_number: 0, op_number: 1, commit_number: 0, requests: vec![request] };
+        let effects = backup.on_message(prepare.clone(), 0);
+        assert!(matches!(effects.as_slice(), [Effect::Failed { error }] if error == "disk full"), "{:?}", effects);
+        assert_eq!(backup.status, Status::Failed);
+        assert!(backup.log.is_empty());
+
+        // It takes no part in the protocol any more.
+        assert!(backup.on_message(prepare, 10).is_empty());
+        assert!(backup.tick(10000).is_empty());
+    }
+
+    #[test]
+    fn test_lagging_backup_catches_up_from_a_checkpoint() {
+        let mut sim = Simulator::<Op>::new(None);
+        let mut states = Vec::new();
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 4, 3, link.clone(), |replica| {
+            let state = Rc::new(RefCell::new(KvStore::default()));
+            replica.state_machine = state.clone();
+            replica.set_checkpoint_interval(2);
+            states.push(state);
+        });
+        let down = Link { up: false, ..link.clone() };
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), down);
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            sim.start_client_request(NodeId(i as u64), Op::Set(key.to_string(), i as u64));
+            sim.run_until(1000 * (i as u64 + 1));
+        }
+
+        let primary = sim.get_replicas().into_iter().find(|r| r.replica_number == 0).unwrap();
+        assert_eq!(primary.checkpoint.as_ref().map(|c| c.op_number), Some(2));
+        assert_eq!(primary.log.len(), 1);
+
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), link);
+        sim.start_client_request(NodeId(3), Op::Set("d".to_string(), 3));
+        sim.run_until(6000);
+
+        let backup = sim.get_replicas().into_iter().find(|r| r.replica_number == 2).unwrap();
+        assert_eq!(backup.commit_number, 4);
+        assert_eq!(states[2].borrow().state, states[0].borrow().state);
+        assert_eq!(states[2].borrow().state.len(), 4);
+    }
+
+    #[test]
+    fn test_backup_without_snapshots_stops_on_a_checkpoint() {
+        /// The key-value store, without a `Snapshot` to restore checkpoints with.
+        #[derive(Debug, Default)]
+        struct NoSnapshots(KvStore);
+
+        impl StateMachine for NoSnapshots {
+            type Input = Op;
+            type Output = Op;
+
+            fn apply(&mut self, input: Op) -> Op {
+                self.0.apply(input)
+            }
+        }
+
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 4, 3, link.clone(), |replica| {
+            replica.set_checkpoint_interval(2);
+            if replica.replica_number == 2 {
+                replica.state_machine = Rc::new(RefCell::new(NoSnapshots::default()));
+            }
+        });
+        let down = Link { up: false, ..link.clone() };
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), down);
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            sim.start_client_request(NodeId(i as u64), Op::Set(key.to_string(), i as u64));
+            sim.run_until(1000 * (i as u64 + 1));
+        }
+
+        // The backup can only catch up from the checkpoint, which it cannot restore, so it stops instead of panicking.
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), link);
+        sim.start_client_request(NodeId(3), Op::Set("d".to_string(), 3));
+        sim.run_until(6000);
+
+        let replicas = sim.get_replicas();
+        assert!(replicas.iter().all(|r| r.replica_number != 2));
+        assert!(replicas.iter().all(|r| r.commit_number == 4));
+    }
+
+    #[test]
+    fn test_backup_stops_on_a_checkpoint_it_cannot_read() {
+        let mut backup = setup_replica(1, vec![0, 1, 2]);
+        let checkpoint = Checkpoint { op_number: 2, data: b"a=1\nb".to_vec(), client_table: Default::default() };
+        let new_state = Message::NewState { epoch: 0, view_number: 0, log: vec![], checkpoint: Some(checkpoint), op_number: 2, commit_number: 2 };
+        let effects = backup.on_message(new_state, 0);
+        assert!(matches!(effects.as_slice(), [Effect::Failed { error }] if error.contains("invalid snapshot")), "{:?}", effects);
+        assert_eq!((backup.status, backup.commit_number), (Status::Failed, 0));
+    }
+
+    #[test]
+    fn test_new_primary_answers_a_retried_request_from_the_client_table() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 4, 3, link.clone(), |replica| replica.set_checkpoint_interval(2));
+        let down = Link { up: false, ..link.clone() };
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)), down.clone());
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            sim.start_client_request(NodeId(i as u64), Op::Set(key.to_string(), i as u64));
+            sim.run_until(1000 * (i as u64 + 1));
+        }
+
+        // Replica 1 only learns the first ops from a checkpoint, which carries their client table.
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)), link);
+        sim.start_client_request(NodeId(3), Op::Set("d".to_string(), 3));
+        sim.run_until(6000);
+
+        let replicas = sim.get_replicas();
+        let backup = replicas.iter().find(|r| r.replica_number == 1).unwrap();
+        assert!(backup.checkpoint.is_some());
+        assert_eq!(backup.client_table.len(), 4);
+        for replica in &replicas {
+            assert_eq!(replica.client_table, backup.client_table);
+        }
+
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)), down.clone());
+        sim.set_link(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(2)), down);
+        sim.run_until(12000);
+
+        // The retried first request of client 0 gets its reply again, and is not appended to the log.
+        let mut primary = sim.get_replicas().into_iter().find(|r| r.replica_number == 1).unwrap().clone();
+        assert_eq!((primary.view_number, primary.status.clone()), (1, Status::Normal));
+        let op_number = primary.op_number;
+        let client_id = sim.get_clients().into_iter().find(|c| c.id == NodeId(0)).unwrap().client_id();
+        let retry = ClientRequest {
+            op: Operation::Apply(Op::Set("a".to_string(), 0)),
+            client_id,
+            request_number: 1,
+            result: None,
+        };
+        let effects = primary.on_message(Message::Request(retry), 12000);
+        assert!(matches!(
+            effects.as_slice(),
+            [Effect::Reply { client_id: id, message: Message::Reply { request_id: 1, result: Some(Op::Set(_, 0)), .. } }]
+                if *id == client_id
+        ));
+        assert_eq!(primary.op_number, op_number);
+    }
+
+    #[test]
+    fn test_registered_sessions_are_evicted_in_log_order() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 3, 3, link, |replica| replica.set_max_clients(2));
+        let clients = [NodeId(0), NodeId(1), NodeId(2)];
+        let client = |sim: &Simulator<Op>, id: NodeId| sim.get_clients().into_iter().find(|c| c.id == id).unwrap().clone();
+
+        // A client that never registered is turned away.
+        sim.start_client_request(clients[0], Op::Set("a".to_string(), 0));
+        sim.run_until(1000);
+        assert_eq!(client(&sim, clients[0]).pending, None);
+        assert!(sim.history().entries[0].response.is_none());
+
+        sim.start_client_registration(clients[0]);
+        sim.run_until(2000);
+        sim.start_client_registration(clients[1]);
+        sim.run_until(3000);
+        assert_eq!(client(&sim, clients[0]).session, Some(1));
+        assert_eq!(client(&sim, clients[1]).session, Some(2));
+
+        // Client 0 used its session after client 1 registered, so client 1 is evicted by the next one. The reply
+        // goes to client 0, even though client 1 has the node id equal to that session.
+        sim.start_client_request(clients[0], Op::Set("a".to_string(), 1));
+        sim.run_until(4000);
+        assert_eq!(client(&sim, clients[0]).state.get("a"), Some(&1));
+        assert!(client(&sim, clients[1]).state.is_empty());
+        sim.start_client_registration(clients[2]);
+        sim.run_until(5000);
+        assert_eq!(client(&sim, clients[2]).session, Some(4));
+
+        sim.start_client_request(clients[1], Op::Set("b".to_string(), 2));
+        sim.run_until(6000);
+        let evicted = client(&sim, clients[1]);
+        assert_eq!((evicted.session, evicted.pending), (None, None));
+        assert_eq!(evicted.state.get("b"), None);
+        assert_eq!(client(&sim, clients[2]).session, Some(4));
+
+        // Every replica evicted the same client as it committed the registrations.
+        let replicas = sim.get_replicas();
+        for replica in &replicas {
+            assert_eq!(replica.client_table.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 4]);
+            assert_eq!(replica.client_table, replicas[0].client_table);
+        }
+
+        // Registering again gives the client a new session to send its requests under.
+        sim.start_client_registration(clients[1]);
+        sim.run_until(7000);
+        sim.start_client_request(clients[1], Op::Set("b".to_string(), 3));
+        sim.run_until(8000);
+        let registered = client(&sim, clients[1]);
+        assert_eq!(registered.session, Some(5));
+        assert_eq!(registered.state.get("b"), Some(&3));
+    }
+
+    #[test]
+    fn test_retried_registration_gets_its_session_back() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 1, 3, link.clone(), |replica| replica.set_max_clients(1));
+
+        // The `Register` arrives twice while it is prepared, and its replies are lost, so the client sends it again
+        // once it committed.
+        let duplicating = Link { dup_pct: 100, ..link };
+        sim.set_link(NodeKind::Client(NodeId(0)), NodeKind::Replica(NodeId(0)), duplicating);
+        sim.cut(NodeKind::Replica(NodeId(0)), NodeKind::Client(NodeId(0)), 0);
+        sim.start_client_registration(NodeId(0));
+        sim.heal(1050);
+        sim.run_until(2000);
+
+        // A second session would have evicted the first one, as only one client fits.
+        let client = sim.get_clients().into_iter().next().unwrap();
+        assert_eq!(client.session, Some(1));
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.op_number, 1);
+            assert_eq!(replica.client_table.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1]);
+        }
+    }
+
+    #[test]
+    fn test_bad_client_requests_get_typed_errors() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 1, 3);
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+        sim.run_until(1000);
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 2));
+        sim.run_until(2000);
+
+        let client_id = sim.get_clients()[0].client_id();
+        let request = |request_number| {
+            Message::Request(ClientRequest {
+                op: Operation::Apply(Op::Set("a".to_string(), 0)),
+                client_id,
+                request_number,
+                result: None,
+            })
+        };
+        let error = |effects: Vec<Effect<Op, Op>>| match effects.as_slice() {
+            [Effect::Reply { client_id: id, message: Message::Error { error } }] if *id == client_id => error.clone(),
+            effects => panic!("expected an error reply, got {:?}", effects),
+        };
+
+        let replica = |number| sim.get_replicas().into_iter().find(|r| r.replica_number == number).unwrap().clone();
+        let mut primary = replica(0);
+        let stale = ReplicaError::StaleRequest { client_id, request_number: 1, last_request_number: 2 };
+        assert_eq!(error(primary.on_message(request(1), 2000)), stale);
+
+        let mut backup = replica(1);
+        assert_eq!(error(backup.on_message(request(3), 2000)), ReplicaError::NotPrimary { view_number: 0, primary: 0 });
+
+        let mut recovering = setup_replica(2, vec![0, 1, 2]);
+        recovering.recover(7, 0);
+        let wrong_view = ReplicaError::WrongView { view_number: 0, status: Status::Recovering };
+        assert_eq!(error(recovering.on_message(request(3), 0)), wrong_view);
+
+        // The client follows the primary a backup points to.
+        let mut client = Client::new(NodeId(0), vec![0, 1, 2]);
+        client.next_request();
+        let not_primary = ReplicaError::NotPrimary { view_number: 1, primary: 1 };
+        client.on_message(Event::<Op>::Msg(Message::Error { error: not_primary }));
+        assert_eq!((client.primary(), client.pending), (1, Some((1, 1))));
+    }
+
+    #[test]
+    fn test_malformed_peer_messages_are_dropped_and_counted() {
+        let mut replica = setup_replica(1, vec![0, 1, 2]);
+        let stray = vec![
+            Message::Connect { configuration: vec![], current_view: 0, epoch: 0 },
+            Message::Reply { client_id: 0, view_number: 0, request_id: 1, result: None },
+            Message::Error { error: ReplicaError::ReconfigurationPending },
+            // Replica 7 is not in the configuration, so its vote could fake a quorum.
+            Message::StartViewChange { epoch: 0, view_number: 1, replica_number: 7 },
+            Message::Commit { op_number: 1, commit_number: 2, epoch: 0, view_number: 0 },
+        ];
+        for message in stray {
+            assert!(replica.on_message(message, 0).is_empty());
+        }
+        assert_eq!(replica.malformed_messages, 5);
+        assert_eq!((replica.status, replica.commit_number), (Status::Normal, 0));
+
+        // Clients drop what is not meant for them.
+        let mut client = Client::new(NodeId(0), vec![0, 1, 2]);
+        client.on_message(Event::<Op>::Msg(Message::Connect { configuration: vec![], current_view: 0, epoch: 0 }));
+        client.on_message(Event::<Op>::Msg(Message::Recovery { replica_number: 1, nonce: 7 }));
+        assert_eq!(client.pending, None);
+    }
+
+    #[test]
+    fn test_primary_prepares_requests_in_batches() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 3, 3, link, |replica| {
+            replica.set_batching(Batching { max_ops: 10, max_bytes: usize::MAX, flush_delay_ms: 50 });
+        });
+        for i in 0..3 {
+            sim.start_client_request(NodeId(i), Op::Set(format!("k{}", i), i));
+        }
+
+        // The requests wait at the primary for the flush timer.
+        sim.run_until(200);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.log.len(), if replica.replica_number == 0 { 3 } else { 0 });
+        }
+
+        sim.run_until(1500);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.commit_number, 3);
+        }
+
+        // The acknowledgements of committed ops are dropped.
+        let primary = sim.get_replicas().into_iter().find(|r| r.replica_number == 0).unwrap();
+        assert!(primary.op_ack_table.is_empty());
+    }
+
+    #[test]
+    fn test_primary_keeps_a_bounded_window_of_prepares() {
+        let mut sim = Simulator::<Op>::new(None);
+        let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+        setup_clients_and_configured_replicas(&mut sim, 5, 3, link, |replica| replica.set_max_in_flight(2));
+        for i in 0..5 {
+            sim.start_client_request(NodeId(i), Op::Set(format!("k{}", i), i));
+        }
+
+        // Only the first two ops are prepared, the others wait for them to commit.
+        sim.run_until(250);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.log.len(), if replica.replica_number == 0 { 5 } else { 2 });
+        }
+
+        sim.run_until(300);
+        let primary = sim.get_replicas().into_iter().find(|r| r.replica_number == 0).unwrap();
+        assert_eq!(primary.commit_number, 2);
+        assert_eq!(primary.op_ack_table.keys().min(), Some(&3));
+
+        sim.run_until(2000);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.commit_number, 5);
+        }
+        assert!(sim.history().entries.iter().all(|e| e.response.is_some()));
+    }
+
+    #[test]
+    fn test_prepare_oks_commit_every_op_up_to_the_quorum_in_order() {
+        let mut primary = setup_replica(0, vec![0, 1, 2, 3, 4]);
+        for client_id in 0..3 {
+            let request = ClientRequest { op: Operation::Apply(Op::Set("a".to_string(), client_id)), client_id, request_number: 1, result: None };
+            primary.on_message(Message::Request(request), 0);
+        }
+
+        let prepare_ok = |replica_number, op_number| Message::PrepareOk { epoch: 0, view_number: 0, replica_number, op_number, commit_number: 0 };
+        let replies = |effects: Vec<Effect<Op, Op>>| {
+            effects.into_iter().filter_map(|e| match e {
+                Effect::Reply { client_id, .. } => Some(client_id),
+                _ => None,
+            }).collect::<Vec<_>>()
+        };
+
+        // A duplicated ack does not make a quorum.
+        assert!(replies(primary.on_message(prepare_ok(1, 3), 10)).is_empty());
+        assert!(replies(primary.on_message(prepare_ok(1, 3), 10)).is_empty());
+        assert_eq!(primary.commit_number, 0);
+
+        // An ack for op 2 arriving after one for op 3 still completes the quorum of ops 1 and 2.
+        assert_eq!(replies(primary.on_message(prepare_ok(2, 2), 20)), vec![0, 1]);
+        assert_eq!(primary.commit_number, 2);
+        assert_eq!(replies(primary.on_message(prepare_ok(3, 3), 30)), vec![2]);
+        assert_eq!(primary.commit_number, 3);
+        assert!(primary.op_ack_table.is_empty());
+    }
+
+    #[test]
+    fn test_duplicated_votes_do_not_fake_a_quorum() {
+        let sends_do_view_change = |effects: Vec<Effect<Op, Op>>| {
+            effects.iter().any(|e| matches!(e, Effect::Send { message: Message::DoViewChange { .. }, .. }))
+        };
+
+        // Replica 2 votes for view 1 itself, and needs two more votes out of five.
+        let mut backup = setup_replica(2, vec![0, 1, 2, 3, 4]);
+        let start_view_change = |replica_number| Message::StartViewChange { epoch: 0, view_number: 1, replica_number };
+        assert!(!sends_do_view_change(backup.on_message(start_view_change(3), 0)));
+        assert!(!sends_do_view_change(backup.on_message(start_view_change(3), 0)));
+        assert_eq!(backup.status, Status::ViewChange);
+        assert!(sends_do_view_change(backup.on_message(start_view_change(4), 0)));
+
+        // A recovering replica needs three distinct responses, one of them from the primary.
+        let mut recovering = setup_replica(4, vec![0, 1, 2, 3, 4]);
+        recovering.recover(7, 0);
+        let response = |replica_number| Message::RecoveryResponse {
+            view_number: 0,
+            nonce: 7,
+            log: (replica_number == 0).then(Vec::new),
+            checkpoint: None,
+            op_number: (replica_number == 0).then_some(0),
+            commit_number: (replica_number == 0).then_some(0),
+            replica_number,
+        };
+        for replica_number in [0, 1, 1, 0] {
+            recovering.on_message(response(replica_number), 0);
+            assert_eq!(recovering.status, Status::Recovering);
+        }
+        recovering.on_message(response(2), 0);
+        assert_eq!(recovering.status, Status::Normal);
+    }
+
+    #[test]
+    fn test_reads_do_not_go_through_the_log() {
+        // Under a lease the primary answers at once, otherwise after a round of heartbeats.
+        for (read_mode, answered_at) in [(ReadMode::Quorum, 400), (ReadMode::Lease { duration_ms: 2000 }, 200)] {
+            let mut sim = Simulator::<Op>::new(None);
+            let link = Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true };
+            setup_clients_and_configured_replicas(&mut sim, 2, 3, link, |replica| replica.set_read_mode(read_mode.clone()));
+
+            sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+            sim.run_until(1500);
+            let sent_at = sim.now;
+            sim.start_client_read(NodeId(1), Op::Get("a".to_string(), None));
+            sim.run_until(sent_at + answered_at - 1);
+            assert!(sim.get_clients().iter().all(|c| c.id != NodeId(1) || c.state.is_empty()));
+
+            sim.run_until(sent_at + answered_at);
+            let client = sim.get_clients().into_iter().find(|c| c.id == NodeId(1)).unwrap();
+            assert_eq!(client.state.get("a"), Some(&1), "{:?}", read_mode);
+            for replica in sim.get_replicas() {
+                assert_eq!(replica.log.len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_backup_waits_for_the_lease_it_granted_before_a_view_change() {
+        let mut backup = setup_replica(1, vec![0, 1, 2]);
+        backup.set_read_mode(ReadMode::Lease { duration_ms: 8000 });
+        backup.tick(0);
+        backup.on_message(Message::Heartbeat { epoch: 0, view_number: 0, commit_number: 0, read_number: 1 }, 1000);
+        let starts_view_change = |effects: Vec<Effect<Op, Op>>| {
+            effects.iter().any(|e| matches!(e, Effect::Broadcast { message: Message::StartViewChange { .. }, .. }))
+        };
+
+        // The watchdog fires while the lease lasts, and waits for it instead.
+        let effects = backup.tick(6000);
+        assert!(!starts_view_change(effects.clone()));
+        assert!(effects.iter().any(|e| matches!(e, Effect::SetTimer { kind: TimerKind::BackupWatchdog, at: 9000 })));
+        assert_eq!(backup.status, Status::Normal);
+
+        assert!(starts_view_change(backup.tick(9000)));
+        assert_eq!((backup.view_number, backup.status), (1, Status::ViewChange));
+    }
+
+    #[test]
+    fn test_same_seed_reproduces_the_run() {
+        fn run(seed: u64) -> (u64, Vec<(u64, u64, usize, usize)>) {
+            let mut sim = Simulator::<Op>::new(Some(SimulatorConfig { seed, ..Default::default() }));
+            let link = Link { base_ms: 100, jitter_ms: 50, drop_pct: 10, dup_pct: 10, up: true };
+            setup_clients_and_configured_replicas(&mut sim, 4, 3, link, |_| {});
+            for i in 0..4 {
+                sim.start_client_request(NodeId(i), Op::Set(format!("k{}", i), i));
+            }
+
+            sim.run_until(5000);
+            let mut replicas = sim
+                .get_replicas()
+                .into_iter()
+                .map(|r| (r.replica_number, r.view_number, r.op_number, r.commit_number))
+                .collect::<Vec<_>>();
+            replicas.sort();
+            (sim.now, replicas)
+        }
+
+        for seed in 0..10 {
+            assert_eq!(run(seed), run(seed), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_isolated_primary_is_replaced_and_rejoins_after_heal() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 0, 3);
+
+        let replicas = (0..3).map(|i| NodeKind::Replica(NodeId(i))).collect::<Vec<_>>();
+        sim.partition(vec![replicas[..1].to_vec(), replicas[1..].to_vec()], 500);
+        sim.heal(8000);
+
+        sim.run_until(7999);
+        for replica in sim.get_replicas() {
+            let view_number = if replica.replica_number == 0 { 0 } else { 1 };
+            assert_eq!(replica.view_number, view_number);
+        }
+
+        sim.run_until(12000);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.view_number, 1);
+            assert_eq!(replica.status, Status::Normal);
+        }
+    }
+
+    #[test]
+    fn test_one_way_cut_keeps_the_other_direction() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 0, 3);
+
+        // The primary still hears its backups, but backup 1 no longer hears the primary and starts a view change.
+        sim.cut(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)), 500);
+        sim.run_until(7999);
+
+        let links = sim.get_links();
+        assert!(!links.0[&(NodeKind::Replica(NodeId(0)), NodeKind::Replica(NodeId(1)))].up);
+        assert!(links.0[&(NodeKind::Replica(NodeId(1)), NodeKind::Replica(NodeId(0)))].up);
+        let backup = sim.get_replicas().into_iter().find(|r| r.replica_number == 1).unwrap();
+        assert!(backup.view_number > 0);
+    }
+
+    #[test]
+    fn test_crashed_replica_restarts_from_its_durable_state() {
+        for mode in [RestartMode::Durable, RestartMode::Amnesia] {
+            let mut sim = Simulator::<Op>::new(None);
+            setup_clients_and_replicas(&mut sim, 2, 3);
+            sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2], state));
+
+            let clients = sim.get_clients();
+            sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+            sim.crash(NodeId(2), 1500);
+            sim.run_until(2000);
+            assert!(sim.is_crashed(NodeId(2)));
+            assert_eq!(sim.get_replicas().len(), 2);
+
+            // The other two replicas still form a quorum.
+            sim.start_client_request(clients[1].id, Op::Set("b".to_string(), 2));
+            sim.restart(NodeId(2), mode, 3000);
+            sim.run_until(12000);
+
+            for replica in sim.get_replicas() {
+                assert_eq!(replica.status, Status::Normal, "{:?}", mode);
+                assert_eq!(replica.op_number, 2, "{:?}", mode);
+                assert_eq!(replica.commit_number, 2, "{:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cluster_survives_f_simultaneous_crashes() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 1, 5);
+        sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2, 3, 4], state));
+
+        sim.crash(NodeId(0), 500);
+        sim.crash(NodeId(3), 500);
+        sim.run_until(8000);
+
+        // The new primary is replica 1, which the client does not know about yet.
+        sim.set_link(NodeKind::Client(NodeId(0)), NodeKind::Replica(NodeId(1)), Link { base_ms: 100, jitter_ms: 0, drop_pct: 0, dup_pct: 0, up: true });
+        let mut client = Client::new(NodeId(0), vec![0, 1, 2, 3, 4]);
+        client.current_view = 1;
+        sim.add_client(NodeId(0), client);
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+        sim.run_until(9000);
+
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.view_number, 1);
+            assert_eq!(replica.commit_number, 1);
+        }
+
+        sim.restart(NodeId(0), RestartMode::Durable, 9000);
+        sim.restart(NodeId(3), RestartMode::Amnesia, 9000);
+        sim.run_until(20000);
+        assert_eq!(sim.get_replicas().len(), 5);
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.status, Status::Normal);
+            assert_eq!(replica.op_number, 1);
+        }
+    }
+
+    #[test]
+    fn test_random_crashes_keep_at_most_f_replicas_down() {
+        let crash_faults = CrashFaults { interval_ms: 1000, crash_pct: 20, max_crashed: 1, down_ms: 2000, amnesia_pct: 50 };
+        for seed in 0..5 {
+            let config = SimulatorConfig { seed, crash_faults: Some(crash_faults.clone()), ..Default::default() };
+            let mut sim = Simulator::<Op>::new(Some(config));
+            setup_clients_and_replicas(&mut sim, 0, 3);
+            sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2], state));
+
+            let mut crashes = 0;
+            for t in (0..60000).step_by(100) {
+                sim.run_until(t);
+                let down = (0..3).filter(|i| sim.is_crashed(NodeId(*i))).count();
+                assert!(down <= 1, "seed {}", seed);
+                crashes += down;
+            }
+            assert!(crashes > 0, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_history_is_linearizable_under_faults() {
+        for seed in 0..20 {
+            let mut sim = Simulator::<Op>::new(Some(SimulatorConfig { seed, ..Default::default() }));
+            let link = Link { base_ms: 100, jitter_ms: 80, drop_pct: 5, dup_pct: 5, up: true };
+            setup_clients_and_configured_replicas(&mut sim, 12, 3, link, |_| {});
+            sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2], state));
+            sim.crash(NodeId(2), 700);
+            sim.restart(NodeId(2), RestartMode::Durable, 1500);
+
+            for i in 0..12 {
+                let key = ["a", "b"][i as usize % 2].to_string();
+                sim.run_until(i * 150);
+                match i % 4 {
+                    0 | 1 => sim.start_client_request(NodeId(i), Op::Set(key, i)),
+                    2 => sim.start_client_read(NodeId(i), Op::Get(key, None)),
+                    _ => sim.start_client_request(NodeId(i), Op::Get(key, None)),
+                };
+            }
+
+            sim.run_until(10000);
+            let history = sim.history();
+            assert_eq!(history.entries.len(), 12);
+            assert!(history.entries.iter().any(|e| matches!(e.response, Some((_, Op::Get(_, Some(_)))))), "seed {}", seed);
+            if let Err(err) = check_linearizable(history) {
+                panic!("seed {}: {:#?}", seed, err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invariants_catch_divergent_commits() {
+        let request = |value| ClientRequest { op: Operation::Apply(Op::Set("a".to_string(), value)), client_id: 0, request_number: 0, result: None };
+        let mut a = setup_replica(0, vec![0, 1, 2]);
+        let mut b = setup_replica(1, vec![0, 1, 2]);
+        a.log.push((1, request(1)));
+        b.log.push((1, request(2)));
+        a.commit_number = 1;
+
+        // Only the entries both replicas committed have to agree.
+        let replicas = [(NodeId(0), &a), (NodeId(1), &b)];
+        let mut no_divergent_commits = NoDivergentCommits::default();
+        assert!(no_divergent_commits.check(&replicas).is_ok());
+
+        // Even once the first replica is gone, what it committed is remembered.
+        b.commit_number = 1;
+        let replicas = [(NodeId(0), &a), (NodeId(1), &b)];
+        assert!(no_divergent_commits.check(&replicas[1..]).is_err());
+
+        // Ops compacted into a checkpoint are compared through the state it holds.
+        let checkpoint = |data: &str| Some(Checkpoint { op_number: 1, data: data.as_bytes().to_vec(), client_table: Default::default() });
+        let (mut a, mut b) = (setup_replica(0, vec![0, 1, 2]), setup_replica(1, vec![0, 1, 2]));
+        (a.checkpoint, b.checkpoint) = (checkpoint("a=1"), checkpoint("a=1"));
+        let mut no_divergent_commits = NoDivergentCommits::default();
+        assert!(no_divergent_commits.check(&[(NodeId(0), &a), (NodeId(1), &b)]).is_ok());
+        b.checkpoint = checkpoint("a=2");
+        assert!(no_divergent_commits.check(&[(NodeId(1), &b)]).is_err());
+    }
+
+    #[test]
+    fn test_violation_stops_the_run_with_seed_and_step() {
+        struct NothingCommits;
+
+        impl Invariant<Op> for NothingCommits {
+            fn name(&self) -> &str {
+                "nothing commits"
+            }
+
+            fn check(&mut self, replicas: &[(NodeId, &Replica<Op, Op>)]) -> Result<(), String> {
+                match replicas.iter().find(|(_, r)| r.commit_number > 0) {
+                    Some((id, _)) => Err(format!("{:?} committed", id)),
+                    None => Ok(()),
+                }
+            }
+        }
+
+        let config = SimulatorConfig { seed: 42, record_violations: true, ..Default::default() };
+        let mut sim = Simulator::<Op>::new(Some(config));
+        setup_clients_and_replicas(&mut sim, 1, 3);
+        sim.add_invariant(NothingCommits);
+
+        let clients = sim.get_clients();
+        sim.start_client_request(clients[0].id, Op::Set("a".to_string(), 1));
+        sim.run_until(5000);
+
+        let violation = sim.violation().unwrap();
+        assert_eq!(violation.invariant, "nothing commits");
+        assert_eq!(violation.seed, 42);
+        assert_eq!(violation.step, sim.steps());
+        assert!(sim.now < 5000);
+    }
+
+    #[test]
+    fn test_trace_replays_the_run() {
+        fn setup(seed: u64) -> Simulator<Op> {
+            let mut sim = Simulator::<Op>::new(Some(SimulatorConfig { seed, trace: true, ..Default::default() }));
+            setup_clients_and_replicas(&mut sim, 3, 3);
+            sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2], state));
+            let link = Link { base_ms: 100, jitter_ms: 40, drop_pct: 5, dup_pct: 5, up: true };
+            for client in 0..3 {
+                sim.set_link(NodeKind::Client(NodeId(client)), NodeKind::Replica(NodeId(0)), link.clone());
+            }
+            sim
+        }
+
+        let mut sim = setup(7);
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+        sim.crash(NodeId(2), 300);
+        sim.restart(NodeId(2), RestartMode::Amnesia, 900);
+        sim.run_until(1000);
+        sim.start_client_request(NodeId(1), Op::Set("b".to_string(), 2));
+        sim.partition(vec![vec![NodeKind::Replica(NodeId(0))], vec![NodeKind::Replica(NodeId(1)), NodeKind::Replica(NodeId(2))]], 1500);
+        sim.heal(7000);
+        sim.run_until(9000);
+        sim.start_client_request(NodeId(2), Op::Del("a".to_string()));
+        sim.run_until(10000);
+
+        let path = std::env::temp_dir().join(format!("vr-simulator-trace-{}.jsonl", std::process::id()));
+        sim.write_trace(&path).unwrap();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(trace.lines().next().unwrap().starts_with(r#"{"type":"start","seed":7"#));
+        assert!(trace.lines().any(|l| l.starts_with(r#"{"type":"effect""#)));
+
+        assert_eq!(setup(7).replay(&trace), Ok(()));
+        assert!(matches!(setup(8).replay(&trace), Err(ReplayError::Diverged { line: 1, .. })));
+
+        // Dropping one client request from the trace changes the outcome.
+        let tampered = trace.lines().filter(|l| !l.contains(r#""Del":"a""#) || !l.starts_with(r#"{"type":"input""#)).collect::<Vec<_>>();
+        let Err(ReplayError::Diverged { line, .. }) = setup(7).replay(&tampered.join("\n")) else {
+            panic!("the tampered trace replayed");
+        };
+        assert!(line > 1);
+    }
+
+    #[test]
+    fn test_client_finds_the_new_primary_after_a_view_change() {
+        let mut sim = Simulator::<Op>::new(None);
+        setup_clients_and_replicas(&mut sim, 1, 3);
+        sim.set_replica_factory(|id, state| restart_replica(id, vec![0, 1, 2], state));
+        let link = Link { base_ms: 100, jitter_ms: 10, drop_pct: 0, dup_pct: 0, up: true };
+        for replica in 1..3 {
+            sim.set_link(NodeKind::Client(NodeId(0)), NodeKind::Replica(NodeId(replica)), link.clone());
+        }
+
+        // The request reaches the primary, which crashes before it commits.
+        sim.start_client_request(NodeId(0), Op::Set("a".to_string(), 1));
+        sim.crash(NodeId(0), 150);
+        sim.run_until(8000);
+
+        let client = sim.get_clients().into_iter().next().unwrap();
+        assert_eq!(client.pending, None);
+        assert_eq!(client.current_view, 1);
+        assert_eq!(client.state.get("a"), Some(&1));
+        assert!(sim.history().entries[0].response.is_some());
+        for replica in sim.get_replicas() {
+            assert_eq!(replica.commit_number, 1);
+        }
+
+        // The next request goes straight to the new primary.
+        sim.start_client_request(NodeId(0), Op::Set("b".to_string(), 2));
+        sim.run_until(8500);
+        let client = sim.get_clients().into_iter().next().unwrap();
+        assert_eq!((client.request_number, client.pending), (2, None));
+    }
+
+    #[test]
+    fn test_workload_drives_closed_and_open_loop_clients() {
+        fn run(arrival: Arrival, client_count: u64) -> Simulator<Op> {
+            let clients = (0..client_count).map(NodeId).collect::<Vec<_>>();
+            let workload = Workload { clients, keys: 3, distribution: KeyDistribution::HotKey { hot_pct: 50 }, reads: true, arrival, until: 5000, ..Default::default() };
+            let mut sim = Simulator::<Op>::new(Some(SimulatorConfig { seed: 3, workload: Some(workload), ..Default::default() }));
+            setup_clients_and_replicas(&mut sim, client_count, 3);
+            sim.run_until(8000);
+            sim
+        }
+
+        // Every closed-loop client waits for its reply, then thinks for 100ms up to 200ms.
+        let sim = run(Arrival::ClosedLoop { think_ms: 100 }, 4);
+        let entries = &sim.history().entries;
+        for client in 0..4 {
+            let ops = entries.iter().filter(|e| e.client_id == NodeId(client)).collect::<Vec<_>>();
+            assert!(ops.len() > 5, "client {} sent {} ops", client, ops.len());
+            assert!(ops.windows(2).all(|w| w[0].response.as_ref().is_some_and(|(at, _)| w[1].invoked_at >= at + 100)));
+        }
+        check_linearizable(sim.history()).unwrap();
+
+        // Open-loop ops arrive at their own pace, every 50ms on average, with enough clients to take them all.
+        let sim = run(Arrival::OpenLoop { interval_ms: 50 }, 16);
+        let entries = &sim.history().entries;
+        assert!((80..120).contains(&entries.len()), "{} ops", entries.len());
+        assert!(entries.iter().all(|e| e.invoked_at <= 5000));
+        check_linearizable(sim.history()).unwrap();
+
+        let ops = |sim: &Simulator<Op>| sim.history().entries.iter().map(|e| (e.invoked_at, e.op.clone())).collect::<Vec<_>>();
+        assert_eq!(ops(&sim), ops(&run(Arrival::OpenLoop { interval_ms: 50 }, 16)));
+    }
+
+    #[test]
+    fn test_report_summarizes_the_run() {
+        let workload = Workload { clients: vec![NodeId(0), NodeId(1)], arrival: Arrival::ClosedLoop { think_ms: 100 }, until: 6000, ..Default::default() };
+        let mut sim = Simulator::<Op>::new(Some(SimulatorConfig { workload: Some(workload), ..Default::default() }));
+        setup_clients_and_replicas(&mut sim, 2, 3);
+        let link = Link { base_ms: 100, jitter_ms: 10, drop_pct: 0, dup_pct: 0, up: true };
+        for (client, replica) in [(0, 1), (0, 2), (1, 1), (1, 2)] {
+            sim.set_link(NodeKind::Client(NodeId(client)), NodeKind::Replica(NodeId(replica)), link.clone());
+        }
+        let replicas = (0..3).map(|i| NodeKind::Replica(NodeId(i))).collect::<Vec<_>>();
+        sim.partition(vec![replicas[..1].to_vec(), replicas[1..].to_vec()], 2000);
+        sim.run_until(10000);
+
+        let report = sim.report();
+        assert_eq!(report.duration_ms, sim.now);
+        assert_eq!(report.view_changes, 1);
+        assert_eq!(report.completed + report.pending, sim.history().entries.len());
+        assert_eq!(report.throughput.iter().sum::<u64>(), report.completed as u64);
+        // Ops complete before the partition, stall during the view change, and complete again in the new view.
+        assert!(report.throughput[0] > 0 && report.throughput[3] == 0 && report.throughput[5..].iter().sum::<u64>() > 0);
+
+        // A request and its reply take four hops of at least 100ms.
+        let latency = report.latency.clone().unwrap();
+        assert!(latency.p50 >= 400 && latency.p50 <= latency.p90 && latency.p99 <= latency.max);
+
+        assert!(report.messages["Reply"] >= report.completed as u64);
+        assert!(report.messages["Prepare"] > 0 && report.messages["StartView"] > 0);
+        assert!(report.link_bytes[&(NodeKind::Client(NodeId(0)), NodeKind::Replica(NodeId(0)))] > 0);
+        assert!(report.link_bytes[&(NodeKind::Replica(NodeId(1)), NodeKind::Client(NodeId(0)))] > 0);
+
+        let table = report.to_string();
+        assert!(table.contains("PrepareOk") && table.contains("replica 1"));
+    }
+
+    #[test]
+    fn test_fuzzer_passes_random_scenarios() {
+        assert_eq!(Scenario::generate(3), Scenario::generate(3));
+        if let Some((scenario, failure)) = fuzzer::fuzz(0..20) {
+            panic!("seed {} failed: {:?}\n{:#?}", scenario.seed, failure, scenario);
+        }
+    }
+
+    #[test]
+    fn test_shrink_keeps_only_what_the_failure_needs() {
+        let mut scenario = Scenario::generate(1);
+        scenario.workload.push(ScheduledOp { at: 10, view: 0, op: Op::Del("z".to_string()), read: false });
+        scenario.faults.push(Fault::Partition { groups: vec![vec![0], vec![1, 2]], at: 10, heal_at: 20 });
+
+        // Fails whenever the Del of "z" is scheduled, whatever else happens.
+        let shrunk = fuzzer::shrink(scenario, |s| s.workload.iter().any(|op| op.op == Op::Del("z".to_string())));
+
+        assert_eq!(shrunk.workload.len(), 1);
+        assert!(shrunk.faults.is_empty());
+        assert_eq!((shrunk.link.drop_pct, shrunk.link.dup_pct, shrunk.link.jitter_ms), (0, 0, 0));
+    }
+
+    /// Adds replicas that wait for a reconfiguration to join the group, linked with the first `replica_count` replicas.
+    fn add_joining_replicas(sim: &mut Simulator<Op>, ids: &[u64], replica_count: u64) {
+        let configuration = (0..replica_count).collect::<Vec<_>>();
+        for id in ids {
+            let mut replica = setup_replica(*id, configuration.clone());
+            replica.status = Status::Transitioning;
+            sim.add_replica(NodeId(*id), replica);
+        }
+
+        let link = Link { base_ms: 100, jitter_ms: 10, drop_pct: 0, dup_pct: 0, up: true };
+        let replicas = configuration.iter().map(|id| (NodeId(*id), setup_replica(*id, configuration.clone()))).collect();
+        set_link_between_replicas(sim, replicas, link);
+    }
+
     fn setup_clients_and_replicas(sim: &mut Simulator<Op>, client_count: u64, replica_count: u64) {
-        let configuration = (0..replica_count).map(|i| i).collect::<Vec<_>>();
+        let link = Link {
+            base_ms: 100,
+            jitter_ms: 10,
+            drop_pct: 0,
+            dup_pct: 0,
+            up: true,
+        };
+
+        setup_clients_and_configured_replicas(sim, client_count, replica_count, link, |_| {});
+    }
+
+    /// Like `setup_clients_and_replicas`, but with every link set to `link`, and each replica set up by `configure`
+    /// before it is added.
+    fn setup_clients_and_configured_replicas(
+        sim: &mut Simulator<Op>,
+        client_count: u64,
+        replica_count: u64,
+        link: Link,
+        mut configure: impl FnMut(&mut Replica<Op, Op>),
+    ) {
+        let configuration = (0..replica_count).collect::<Vec<_>>();
         let mut replicas = Vec::new();
         for i in 0..replica_count {
-            let replica = setup_replica(i, configuration.clone());
+            let mut replica = setup_replica(i, configuration.clone());
+            configure(&mut replica);
             let node_id = NodeId(i);
-            replicas.push((node_id.clone(), replica.clone()));
+            replicas.push((node_id, replica.clone()));
             sim.add_replica(node_id, replica);
         }
 
@@ -94,61 +1183,31 @@ mod tests {
             sim.add_client(NodeId(i), client);
         }
 
-        let link = Link {
-            base_ms: 100,
-            jitter_ms: 10,
-            drop_pct: 0,
-            dup_pct: 0,
-            up: true,
-        };
-
-        for i in 0..client_count as usize {
-            let client = clients[i].clone();
-            let (node_id, _)= replicas.get(0).unwrap();
-            sim.set_link(NodeKind::Client(client.id), NodeKind::Replica(node_id.clone()), link.clone());
+        for client in &clients {
+            let (node_id, _) = replicas.first().unwrap();
+            sim.set_link(NodeKind::Client(client.id), NodeKind::Replica(*node_id), link.clone());
         }
 
         set_link_between_replicas(sim, replicas, link);
     }
 
     fn setup_replica(id: u64, configuration: Vec<u64>) -> Replica<Op, Op> {
-        let state = Rc::new(RefCell::new(ReplicaState { state: HashMap::new() }));
+        let state = Rc::new(RefCell::new(KvStore::default()));
         Replica::new(configuration, id, state)
     }
 
+    fn restart_replica(id: NodeId, configuration: Vec<u64>, state: DurableState<Op, Op>) -> Replica<Op, Op> {
+        let state_machine = Rc::new(RefCell::new(KvStore::default()));
+        let storage = Rc::new(RefCell::new(MemoryLogStorage::with_state(FsyncPolicy::PerOp, state)));
+        Replica::with_storage(configuration, id.0, state_machine, storage).unwrap()
+    }
+
     fn set_link_between_replicas(sim: &mut Simulator<Op>, replicas: Vec<(NodeId, Replica<Op, Op>)>, link: Link) {
         replicas.iter().for_each(|(node_id, _)| {
             let other_replicas = replicas.iter().filter(|(other_node_id, _)| other_node_id != node_id);
             other_replicas.for_each(|(other_node_id, _)| {
-                sim.set_link(NodeKind::Replica(node_id.clone()), NodeKind::Replica(other_node_id.clone()), link.clone());
+                sim.set_link(NodeKind::Replica(*node_id), NodeKind::Replica(*other_node_id), link.clone());
             });
         });
     }
-
-    #[derive(Debug)]
-    struct ReplicaState {
-        state: HashMap<String, u64>,
-    }
-
-    impl StateMachine for ReplicaState {
-        type Input = Op;
-        type Output = Op;
-
-        fn apply(&mut self, input: Self::Input) -> Self::Output {
-            match input {
-                Op::Set(key, value) => {
-                    self.state.insert(key.clone(), value);
-                    Op::Set(key, value)
-                }
-                Op::Get(key, _) => {
-                    let value = self.state.get(&key).cloned();
-                    Op::Get(key, value)
-                }
-                Op::Del(key) => {
-                    self.state.remove(&key);
-                    Op::Del(key)
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::history::History;
+use crate::simulator::NodeKind;
+
+/// The width of a throughput bucket, in simulated time.
+const BUCKET_MS: u64 = 1000;
+
+/// What the simulator counts while it runs, for `Simulator::report`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Stats {
+    /// Messages sent, by `Message::name`. Copies the network duplicates are not counted.
+    pub(crate) messages: BTreeMap<&'static str, u64>,
+    /// Bytes sent from one node to another, as JSON payloads.
+    pub(crate) link_bytes: BTreeMap<(NodeKind, NodeKind), u64>,
+    pub(crate) view_changes: u64,
+    /// The highest view any replica reached `Status::Normal` in.
+    pub(crate) highest_view: u64,
+}
+
+impl Stats {
+    pub(crate) fn on_send(&mut self, from: NodeKind, to: NodeKind, name: &'static str, bytes: usize) {
+        *self.messages.entry(name).or_default() += 1;
+        *self.link_bytes.entry((from, to)).or_default() += bytes as u64;
+    }
+
+    /// Counts a view change when a replica starts a view above every view seen so far.
+    pub(crate) fn on_normal_view(&mut self, view_number: u64) {
+        if view_number > self.highest_view {
+            self.highest_view = view_number;
+            self.view_changes += 1;
+        }
+    }
+}
+
+/// Latency percentiles of the completed client ops, from when the client sent them to when the reply arrived.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Latency {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+    pub mean: u64,
+}
+
+/// A summary of a simulator run, in simulated time.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub duration_ms: u64,
+    pub completed: usize,
+    /// Ops that never got a reply.
+    pub pending: usize,
+    /// `None` if no op completed.
+    pub latency: Option<Latency>,
+    /// Ops completed in every second of the run, from time 0.
+    pub throughput: Vec<u64>,
+    pub messages: BTreeMap<String, u64>,
+    pub view_changes: u64,
+    pub link_bytes: BTreeMap<(NodeKind, NodeKind), u64>,
+}
+
+impl Report {
+    pub(crate) fn new<Input>(now: u64, history: &History<Input>, stats: &Stats) -> Self {
+        let completed = history.entries.iter().filter_map(|e| e.response.as_ref().map(|(at, _)| (e.invoked_at, *at)));
+        let mut latencies = completed.clone().map(|(invoked_at, at)| at - invoked_at).collect::<Vec<_>>();
+        latencies.sort();
+
+        let mut throughput = vec![0; (now / BUCKET_MS) as usize + 1];
+        for (_, at) in completed {
+            throughput[(at / BUCKET_MS) as usize] += 1;
+        }
+
+        Self {
+            duration_ms: now,
+            completed: latencies.len(),
+            pending: history.entries.len() - latencies.len(),
+            latency: latency(&latencies),
+            throughput,
+            messages: stats.messages.iter().map(|(name, count)| (name.to_string(), *count)).collect(),
+            view_changes: stats.view_changes,
+            link_bytes: stats.link_bytes.clone(),
+        }
+    }
+}
+
+/// Nearest-rank percentiles of sorted latencies.
+fn latency(sorted: &[u64]) -> Option<Latency> {
+    let max = *sorted.last()?;
+    let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100).max(1) - 1];
+    let mean = sorted.iter().sum::<u64>() / sorted.len() as u64;
+
+    Some(Latency { p50: percentile(50), p90: percentile(90), p99: percentile(99), max, mean })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "simulated time: {}ms, view changes: {}", self.duration_ms, self.view_changes)?;
+        writeln!(f)?;
+        writeln!(f, "{:>10} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6}", "completed", "pending", "p50", "p90", "p99", "max", "mean")?;
+        match &self.latency {
+            Some(l) => writeln!(f, "{:>10} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6}", self.completed, self.pending, l.p50, l.p90, l.p99, l.max, l.mean)?,
+            None => writeln!(f, "{:>10} {:>8} {:>6} {:>6} {:>6} {:>6} {:>6}", self.completed, self.pending, "-", "-", "-", "-", "-")?,
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>10} {:>8}", "second", "ops")?;
+        for (second, ops) in self.throughput.iter().enumerate() {
+            writeln!(f, "{:>10} {:>8}", second, ops)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<18} {:>8}", "message", "count")?;
+        for (name, count) in &self.messages {
+            writeln!(f, "{:<18} {:>8}", name, count)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<14} {:<14} {:>10}", "from", "to", "bytes")?;
+        for ((from, to), bytes) in &self.link_bytes {
+            writeln!(f, "{:<14} {:<14} {:>10}", node_name(from), node_name(to), bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn node_name(node: &NodeKind) -> String {
+    match node {
+        NodeKind::Client(id) => format!("client {}", id.0),
+        NodeKind::Replica(id) => format!("replica {}", id.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let sorted = (1..=100).collect::<Vec<_>>();
+        assert_eq!(latency(&sorted), Some(Latency { p50: 50, p90: 90, p99: 99, max: 100, mean: 50 }));
+        assert_eq!(latency(&[7]), Some(Latency { p50: 7, p90: 7, p99: 7, max: 7, mean: 7 }));
+        assert_eq!(latency(&[]), None);
+    }
+}
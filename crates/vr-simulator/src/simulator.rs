@@ -1,10 +1,22 @@
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 
-use vr_replica::message::ClientRequest;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use vr_replica::message::{ClientRequest, Operation, ReadRequest};
+use vr_replica::replica::Status;
+use vr_replica::storage::DurableState;
 use vr_replica::{clock::TimerKind, effect::Effect, message::Message, replica::Replica};
 
-use crate::client::{Client, Op};
+use crate::client::{Client, Op, UNREGISTERED};
 use crate::events::Event;
+use crate::history::History;
+use crate::invariants::{Invariant, Violation, default_invariants};
+use crate::report::{Report, Stats};
+use crate::rng::Rng;
+use crate::trace::{ReplayError, TraceEntry};
+use crate::workload::{Arrival, Generator, Workload};
 
 #[derive(Clone)]
 pub struct Links(pub HashMap<(NodeKind, NodeKind), Link>);
@@ -13,22 +25,22 @@ pub struct Links(pub HashMap<(NodeKind, NodeKind), Link>);
 impl std::fmt::Debug for Links {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for ((a, b), l) in &self.0 {
-            write!(f, "{:?} -> {:?} -> {:?}\n", a, b, l)?;
+            writeln!(f, "{:?} -> {:?} -> {:?}", a, b, l)?;
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct NodeId(pub u64);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum NodeKind {
     Client(NodeId),
     Replica(NodeId),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Link {
     pub up: bool,
     pub base_ms: u64,
@@ -37,52 +49,126 @@ pub struct Link {
     pub dup_pct: u8,
 }
 
-enum WheelEvent<Input> {
-    Deliver(NodeKind),
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WheelEvent<Input: Clone> {
+    Deliver { to: NodeKind, event: Event<Input> },
     FireTimer { node: NodeId, kind: TimerKind },
-    ClientThink { client_id: NodeId, op: Input },
+    ClientThink { client_id: NodeId, op: Operation<Input> },
+    ClientRead { client_id: NodeId, op: Input },
+    /// Resends the request if the client still waits for its reply.
+    ClientTimeout { client_id: NodeId, request_number: u64, request: Message<Input, Op> },
+    Partition(Vec<Vec<NodeKind>>),
+    Cut { from: NodeKind, to: NodeKind },
+    Heal,
+    Crash(NodeId),
+    Restart { node: NodeId, mode: RestartMode },
+    /// Rolls the dice of `SimulatorConfig::crash_faults` for every replica.
+    RandomCrashes,
+    /// The client sends the next op of `SimulatorConfig::workload`, in a closed loop.
+    WorkloadThink(NodeId),
+    /// The next op of `SimulatorConfig::workload` arrives, in an open loop.
+    WorkloadArrival,
+}
+
+/// What a restarted replica starts from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RestartMode {
+    /// The state its `LogStorage` made durable before the crash.
+    Durable,
+    /// Nothing, as after losing its disk. The replica runs the recovery protocol.
+    Amnesia,
+}
+
+/// Crashes replicas at random, and restarts them after a while.
+#[derive(Debug, Clone)]
+pub struct CrashFaults {
+    /// How often every live replica may crash.
+    pub interval_ms: u64,
+    /// The chance a replica crashes at each interval.
+    pub crash_pct: u8,
+    /// The most replicas down at once.
+    pub max_crashed: usize,
+    /// A crashed replica restarts after `down_ms` up to twice that.
+    pub down_ms: u64,
+    /// The chance a restart loses the durable state.
+    pub amnesia_pct: u8,
 }
 
-#[derive(Debug)]
+/// Builds the replica that restarts as `NodeId` from the durable state it left.
+pub type ReplicaFactory<Input> = Box<dyn Fn(NodeId, DurableState<Input, Op>) -> Replica<Input, Op>>;
+
+#[derive(Debug, Default)]
 pub struct SimulatorConfig {
+    /// Seeds the RNG behind link jitter, drops and duplicates. The same seed reproduces the same run.
+    pub seed: u64,
     pub disable_timers: bool,
     pub run_until_max_time: Option<u64>,
+    /// Crashes and restarts replicas at random. Needs `Simulator::set_replica_factory`.
+    pub crash_faults: Option<CrashFaults>,
+    /// Stops the run at the first invariant violation and keeps it in `Simulator::violation`, instead of panicking.
+    pub record_violations: bool,
+    /// Records a trace of the run, see `Simulator::trace`.
+    pub trace: bool,
+    /// Drives the clients of the workload with random ops.
+    pub workload: Option<Workload>,
 }
 
-impl Default for SimulatorConfig {
-    fn default() -> Self {
-        Self {
-            disable_timers: false,
-            run_until_max_time: None,
-        }
-    }
-}
-
-// TODO: Add RNG
 pub struct Simulator<Input: Clone + std::fmt::Debug + 'static> {
     pub now: u64,
     wheel: BTreeMap<u64, Vec<WheelEvent<Input>>>,
 
     replicas: HashMap<NodeId, Replica<Input, Op>>,
-    inbox: HashMap<NodeKind, VecDeque<Event<Input>>>,
+    /// The durable state of the crashed replicas, as they left it.
+    crashed: BTreeMap<NodeId, DurableState<Input, Op>>,
+    replica_factory: Option<ReplicaFactory<Input>>,
     links: Links,
 
     clients: HashMap<NodeId, Client>,
+    history: History<Input>,
+
+    invariants: Vec<Box<dyn Invariant<Input>>>,
+    /// How many steps ran, which locates a violation along with the seed.
+    steps: u64,
+    violation: Option<Violation>,
+    /// The JSON lines of the trace, with `SimulatorConfig::trace`.
+    trace: Option<Vec<String>>,
+
+    workload: Option<Generator>,
+    stats: Stats,
 
     config: SimulatorConfig,
+    rng: Rng,
 }
 
-impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
+impl <Input: Clone + std::fmt::Debug + Serialize + DeserializeOwned + From<Op> + 'static> Simulator<Input> {
     pub fn new(config: Option<SimulatorConfig>) -> Self {
-        Self {
+        let config = config.unwrap_or_default();
+        let seed = config.seed;
+        let mut sim = Self {
             now: 0,
             wheel: BTreeMap::new(),
             replicas: HashMap::new(),
-            inbox: HashMap::new(),
+            crashed: BTreeMap::new(),
+            replica_factory: None,
             links: Links(HashMap::new()),
             clients: HashMap::new(),
-            config: config.unwrap_or_default(),
+            history: History::default(),
+            invariants: default_invariants(),
+            steps: 0,
+            violation: None,
+            trace: config.trace.then(Vec::new),
+            workload: config.workload.clone().map(|workload| Generator::new(workload, seed)),
+            stats: Stats::default(),
+            rng: Rng::new(config.seed),
+            config,
+        };
+
+        sim.record(TraceEntry::Start { seed });
+        if let Some(faults) = &sim.config.crash_faults {
+            sim.schedule(faults.interval_ms, WheelEvent::RandomCrashes);
         }
+        sim.start_workload();
+        sim
     }
 
     pub fn get_clients(&self) -> Vec<Client> {
@@ -90,7 +176,83 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
     }
 
     pub fn get_replicas(&self) -> Vec<&Replica<Input, Op>> {
-        self.replicas.values().map(|r| r).collect()
+        self.replicas.values().collect()
+    }
+
+    /// The operations the clients invoked so far, and the replies they got.
+    pub fn history(&self) -> &History<Input> {
+        &self.history
+    }
+
+    /// Checks `invariant` after every step, besides the default ones.
+    pub fn add_invariant(&mut self, invariant: impl Invariant<Input> + 'static) {
+        self.invariants.push(Box::new(invariant));
+    }
+
+    /// The first invariant violation, with `SimulatorConfig::record_violations`.
+    pub fn violation(&self) -> Option<&Violation> {
+        self.violation.as_ref()
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Latencies, throughput, message counts, view changes and bytes per link of the run so far.
+    pub fn report(&self) -> Report {
+        Report::new(self.now, &self.history, &self.stats)
+    }
+
+    /// The JSON lines of the trace so far: every event scheduled and fired, and every effect of the replicas. Empty
+    /// unless `SimulatorConfig::trace` is set.
+    pub fn trace(&self) -> &[String] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    pub fn write_trace(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut contents = self.trace().join("\n");
+        contents.push('\n');
+        std::fs::write(path, contents)
+    }
+
+    /// Re-drives the run recorded in `trace`, and checks that it goes exactly the same way.
+    ///
+    /// The simulator must be set up like the recorded one: with `SimulatorConfig::trace`, the same seed, and the same
+    /// replicas, clients, links and replica factory. What the recorded run was given while it ran, the client requests
+    /// and the faults, comes from the trace.
+    pub fn replay(&mut self, trace: &str) -> Result<(), ReplayError> {
+        let expected = trace.lines().collect::<Vec<_>>();
+        let mut inputs = Vec::new();
+        let mut last_step = 0;
+        for (i, line) in expected.iter().enumerate() {
+            let entry = serde_json::from_str::<TraceEntry<Input>>(line)
+                .map_err(|err| ReplayError::Malformed { line: i + 1, error: err.to_string() })?;
+
+            match entry {
+                TraceEntry::Input { step, at, event } => inputs.push((step, at, event)),
+                TraceEntry::Fire { step, .. } => last_step = step,
+                _ => {}
+            }
+        }
+
+        for (step, at, event) in inputs {
+            self.run_steps(step);
+            self.input(at, event);
+        }
+        self.run_steps(last_step);
+
+        let actual = self.trace();
+        for i in 0..expected.len().max(actual.len()) {
+            if expected.get(i).copied() != actual.get(i).map(|l| l.as_str()) {
+                return Err(ReplayError::Diverged {
+                    line: i + 1,
+                    expected: expected.get(i).map_or("the end of the trace".to_string(), |l| l.to_string()),
+                    actual: actual.get(i).cloned(),
+                });
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get_links(&self) -> Links {
@@ -102,17 +264,87 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
             return false;
         };
 
-        self.schedule(self.now, WheelEvent::ClientThink { client_id, op });
+        self.input(self.now, WheelEvent::ClientThink { client_id, op: Operation::Apply(op) });
         
         true
     }
 
+    /// Sends `op` as a read, which the primary answers without appending it to the log.
+    pub fn start_client_read(&mut self, client_id: NodeId, op: Input) -> bool {
+        if self.clients.get_mut(&client_id).is_none() {
+            return false;
+        };
+
+        self.input(self.now, WheelEvent::ClientRead { client_id, op });
+
+        true
+    }
+
+    /// Sends a `Register`, after which the client sends its requests under the client id it gets back.
+    pub fn start_client_registration(&mut self, client_id: NodeId) -> bool {
+        if self.clients.get_mut(&client_id).is_none() {
+            return false;
+        };
+
+        self.input(self.now, WheelEvent::ClientThink { client_id, op: Operation::Register });
+
+        true
+    }
+
+    /// Sends a request to move the replica group from `epoch` to a new configuration.
+    ///
+    /// Replicas joining the group must already be added, with `Status::Transitioning`, and linked to the old group.
+    pub fn start_client_reconfiguration(&mut self, client_id: NodeId, epoch: u64, configuration: Vec<u64>) -> bool {
+        if self.clients.get_mut(&client_id).is_none() {
+            return false;
+        };
+
+        let op = Operation::Reconfigure { epoch, configuration };
+        self.input(self.now, WheelEvent::ClientThink { client_id, op });
+
+        true
+    }
+
+    /// Splits the nodes into `groups` at time `at`, by taking down every link between two groups. Nodes in no group
+    /// keep their links.
+    pub fn partition(&mut self, groups: Vec<Vec<NodeKind>>, at: u64) {
+        self.input(at, WheelEvent::Partition(groups));
+    }
+
+    /// Takes down the link from `from` to `to` at time `at`, leaving the other direction as it is.
+    pub fn cut(&mut self, from: NodeKind, to: NodeKind, at: u64) {
+        self.input(at, WheelEvent::Cut { from, to });
+    }
+
+    /// Brings every link back up at time `at`.
+    pub fn heal(&mut self, at: u64) {
+        self.input(at, WheelEvent::Heal);
+    }
+
+    /// Crashes a replica at time `at`. It loses the messages on their way to it, its timers, and whatever its
+    /// `LogStorage` did not make durable.
+    pub fn crash(&mut self, id: NodeId, at: u64) {
+        self.input(at, WheelEvent::Crash(id));
+    }
+
+    /// Restarts a crashed replica at time `at`, through the factory set with `set_replica_factory`.
+    pub fn restart(&mut self, id: NodeId, mode: RestartMode, at: u64) {
+        self.input(at, WheelEvent::Restart { node: id, mode });
+    }
+
+    pub fn set_replica_factory(&mut self, factory: impl Fn(NodeId, DurableState<Input, Op>) -> Replica<Input, Op> + 'static) {
+        self.replica_factory = Some(Box::new(factory));
+    }
+
+    pub fn is_crashed(&self, id: NodeId) -> bool {
+        self.crashed.contains_key(&id)
+    }
+
     pub fn add_replica(&mut self, id: NodeId, r: Replica<Input, Op>) {
         // Only schedule timers based on replica role, and not immediately at time 0
-        let is_primary = r.view_number == r.replica_number;
+        let is_primary = r.is_primary();
         
         self.replicas.insert(id, r);
-        self.inbox.insert(NodeKind::Replica(id), VecDeque::new());
 
         // Schedule initial timers with some delay to avoid immediate firing
         if !self.config.disable_timers {
@@ -125,9 +357,20 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
         }
     }
 
+    /// Starts the recovery protocol on a replica, e.g. after replacing it with a fresh one through `add_replica`.
+    pub fn recover_replica(&mut self, id: NodeId, nonce: u64) -> bool {
+        let Some(r) = self.replicas.get_mut(&id) else {
+            return false;
+        };
+
+        let mut effs = r.recover(nonce, self.now);
+        self.apply_effects(id, &mut effs);
+
+        true
+    }
+
     pub fn add_client(&mut self, id: NodeId, c: Client) {
         self.clients.insert(id, c);
-        self.inbox.insert(NodeKind::Client(id), VecDeque::new());
     }
 
     pub fn set_link(&mut self, src: NodeKind, dst: NodeKind, link: Link) {
@@ -137,21 +380,49 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
 
     pub fn step(&mut self) {
         println!("stepping");
-        let Some((&at, evs)) = self.wheel.iter().next() else {
+        let Some((&at, _)) = self.wheel.iter().next() else {
             println!("no events to step");
             return;
         };
 
         let evs = self.wheel.remove(&at).unwrap();
         self.now = at;
+        self.steps += 1;
 
         for ev in evs {
+            if self.trace.is_some() {
+                self.record(TraceEntry::Fire { step: self.steps, at, event: ev.clone() });
+            }
+
             match ev {
-                WheelEvent::Deliver(to) => self.deliver_one(to),
+                WheelEvent::Deliver { to, event } => self.deliver_one(to, event),
                 WheelEvent::FireTimer { node, kind } => self.fire_timer(NodeKind::Replica(node), kind),
                 WheelEvent::ClientThink { client_id, op } => self.client_think(client_id, op),
+                WheelEvent::ClientRead { client_id, op } => self.client_read(client_id, op),
+                WheelEvent::ClientTimeout { client_id, request_number, request } => self.client_timeout(client_id, request_number, request),
+                WheelEvent::Partition(groups) => self.apply_partition(groups),
+                WheelEvent::Cut { from, to } => {
+                    println!("cutting link: {:?} -> {:?}", from, to);
+                    if let Some(l) = self.links.0.get_mut(&(from, to)) {
+                        l.up = false;
+                    }
+                }
+                WheelEvent::Heal => {
+                    println!("healing all links");
+                    self.links.0.values_mut().for_each(|l| l.up = true);
+                }
+                WheelEvent::Crash(node) => self.crash_replica(node),
+                WheelEvent::Restart { node, mode } => self.restart_replica(node, mode),
+                WheelEvent::RandomCrashes => self.random_crashes(),
+                WheelEvent::WorkloadThink(client_id) => self.workload_think(client_id),
+                WheelEvent::WorkloadArrival => self.workload_arrival(),
             }
         }
+
+        for r in self.replicas.values().filter(|r| r.status == Status::Normal) {
+            self.stats.on_normal_view(r.view_number);
+        }
+        self.check_invariants();
     }
 
     pub fn run(&mut self) {
@@ -161,6 +432,9 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
     pub fn run_until(&mut self, max_time: u64) {
         println!("running until time {}", max_time);
         while let Some((&at, _)) = self.wheel.iter().next() {
+            if self.violation.is_some() {
+                break;
+            }
             if at > max_time {
                 println!("reached max simulation time: {}", max_time);
                 break;
@@ -171,64 +445,322 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
         println!("simulation finished at time {}", self.now);
     }
 
+    fn check_invariants(&mut self) {
+        let mut replicas = self.replicas.iter().map(|(id, r)| (*id, r)).collect::<Vec<_>>();
+        replicas.sort_by_key(|(id, _)| *id);
+
+        for invariant in &mut self.invariants {
+            if let Err(message) = invariant.check(&replicas) {
+                let violation = Violation {
+                    seed: self.config.seed,
+                    step: self.steps,
+                    invariant: invariant.name().to_string(),
+                    message,
+                };
+
+                if !self.config.record_violations {
+                    panic!("{}", violation);
+                }
+                self.violation = Some(violation);
+                return;
+            }
+        }
+    }
+
+    /// Runs until `steps` steps ran in total, or nothing is left to run.
+    fn run_steps(&mut self, steps: u64) {
+        while self.steps < steps && !self.wheel.is_empty() && self.violation.is_none() {
+            self.step();
+        }
+    }
+
+    /// Schedules an event from outside the run, which a replay takes from the trace.
+    fn input(&mut self, at: u64, event: WheelEvent<Input>) {
+        if self.trace.is_some() {
+            self.record(TraceEntry::Input { step: self.steps, at, event: event.clone() });
+        }
+        self.wheel.entry(at).or_default().push(event);
+    }
+
+    fn record(&mut self, entry: TraceEntry<Input>) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(serde_json::to_string(&entry).unwrap());
+        }
+    }
+
     fn schedule(&mut self, at: u64, event: WheelEvent<Input>) {
+        if self.trace.is_some() {
+            self.record(TraceEntry::Schedule { step: self.steps, at, event: event.clone() });
+        }
         self.wheel.entry(at).or_default().push(event);
     }
 
-    fn deliver_one(&mut self, dst: NodeKind) {
-        match dst {
-            NodeKind::Replica(id) => self.deliver_to_replica(id),
-            NodeKind::Client(id) => self.deliver_to_client(id),
+    fn crash_replica(&mut self, id: NodeId) {
+        let Some(r) = self.replicas.remove(&id) else {
+            return;
+        };
+
+        println!("crashing replica: {:?}", id);
+        let state = r.storage.borrow_mut().load().unwrap();
+        self.crashed.insert(id, state);
+
+        let to = NodeKind::Replica(id);
+        for evs in self.wheel.values_mut() {
+            evs.retain(|ev| match ev {
+                WheelEvent::Deliver { to: dst, .. } => *dst != to,
+                WheelEvent::FireTimer { node, .. } => *node != id,
+                _ => true,
+            });
         }
     }
 
-    fn deliver_to_replica(&mut self, dst: NodeId) {
-        if let Some(q) = self.inbox.get_mut(&NodeKind::Replica(dst)) {
-            if let Some(ev) = q.pop_front() {
-                let r = self.replicas.get_mut(&dst).unwrap();
-                let mut effs = match ev {
-                    Event::Msg(m) => r.on_message(m.clone(), self.now),
-                    Event::TimerFired(_) => r.tick(self.now),
-                };
-                self.apply_effects(dst, &mut effs);
+    fn restart_replica(&mut self, id: NodeId, mode: RestartMode) {
+        let Some(state) = self.crashed.remove(&id) else {
+            return;
+        };
+
+        println!("restarting replica: {:?}, {:?}", id, mode);
+        let factory = self.replica_factory.as_ref().expect("restarting a replica needs a replica factory");
+        let state = match mode {
+            RestartMode::Durable => state,
+            RestartMode::Amnesia => DurableState::default(),
+        };
+
+        let r = factory(id, state);
+        self.add_replica(id, r);
+        if mode == RestartMode::Amnesia {
+            let nonce = self.rng.next_u64();
+            self.recover_replica(id, nonce);
+        }
+    }
+
+    fn random_crashes(&mut self) {
+        let Some(faults) = self.config.crash_faults.clone() else {
+            return;
+        };
+
+        let mut ids = self.replicas.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            if self.crashed.len() >= faults.max_crashed || !self.rng.chance(faults.crash_pct) {
+                continue;
+            }
+
+            self.crash_replica(id);
+            let mode = if self.rng.chance(faults.amnesia_pct) { RestartMode::Amnesia } else { RestartMode::Durable };
+            let at = self.now + faults.down_ms + self.rng.up_to(faults.down_ms);
+            self.schedule(at, WheelEvent::Restart { node: id, mode });
+        }
+
+        self.schedule(self.now + faults.interval_ms, WheelEvent::RandomCrashes);
+    }
+
+    fn start_workload(&mut self) {
+        let Some(generator) = &mut self.workload else {
+            return;
+        };
+
+        match generator.workload.arrival {
+            Arrival::ClosedLoop { .. } => {
+                let clients = generator.workload.clients.clone();
+                for client_id in clients {
+                    let at = self.workload_delay();
+                    self.schedule(at, WheelEvent::WorkloadThink(client_id));
+                }
+            }
+            Arrival::OpenLoop { .. } => {
+                let at = self.workload_delay();
+                self.schedule(at, WheelEvent::WorkloadArrival);
             }
         }
     }
 
-    fn deliver_to_client(&mut self, dst: NodeId) {
-        if let Some(q) = self.inbox.get_mut(&NodeKind::Client(dst)) {
-            if let Some(ev) = q.pop_front() {
-                let c = self.clients.get_mut(&dst).unwrap();
-                c.on_message(ev);
+    /// When the next op of the workload is due, from now.
+    fn workload_delay(&mut self) -> u64 {
+        self.now + self.workload.as_mut().map_or(0, |generator| generator.next_delay())
+    }
+
+    fn workload_think(&mut self, client_id: NodeId) {
+        let Some(generator) = &mut self.workload else {
+            return;
+        };
+
+        if self.now > generator.workload.until {
+            return;
+        }
+
+        let next = generator.next_op();
+        if next.read {
+            self.client_read(client_id, next.op.into());
+        } else {
+            self.client_think(client_id, Operation::Apply(next.op.into()));
+        }
+    }
+
+    fn workload_arrival(&mut self) {
+        let Some(generator) = &mut self.workload else {
+            return;
+        };
+
+        if self.now > generator.workload.until {
+            return;
+        }
+
+        let idle = generator
+            .workload
+            .clients
+            .iter()
+            .filter(|id| self.clients.get(id).is_some_and(|c| c.pending.is_none()))
+            .copied()
+            .collect::<Vec<_>>();
+        match generator.pick(&idle) {
+            Some(client_id) => self.workload_think(client_id),
+            None => println!("every workload client is busy, losing an arrival"),
+        }
+
+        let at = self.workload_delay();
+        self.schedule(at, WheelEvent::WorkloadArrival);
+    }
+
+    fn apply_partition(&mut self, groups: Vec<Vec<NodeKind>>) {
+        println!("partitioning: {:?}", groups);
+        let group_of = |node: &NodeKind| groups.iter().position(|g| g.contains(node));
+        for ((a, b), l) in self.links.0.iter_mut() {
+            if let (Some(ga), Some(gb)) = (group_of(a), group_of(b))
+                && ga != gb
+            {
+                l.up = false;
             }
         }
     }
 
+    fn deliver_one(&mut self, dst: NodeKind, ev: Event<Input>) {
+        match dst {
+            NodeKind::Replica(id) => self.deliver_to_replica(id, ev),
+            NodeKind::Client(id) => self.deliver_to_client(id, ev),
+        }
+    }
+
+    fn deliver_to_replica(&mut self, dst: NodeId, ev: Event<Input>) {
+        let Some(r) = self.replicas.get_mut(&dst) else {
+            return;
+        };
+
+        let mut effs = match ev {
+            Event::Msg(m) => r.on_message(m, self.now),
+            Event::TimerFired(_) => r.tick(self.now),
+        };
+        self.apply_effects(dst, &mut effs);
+    }
+
+    fn deliver_to_client(&mut self, dst: NodeId, ev: Event<Input>) {
+        let Some(c) = self.clients.get_mut(&dst) else {
+            return;
+        };
+
+        if let Event::Msg(Message::Reply { request_id, result: Some(output), .. }) = &ev {
+            self.history.complete(dst, *request_id as u64, output.clone(), self.now);
+        }
+        let was_pending = c.pending.is_some();
+        c.on_message(ev);
+        let answered = was_pending && c.pending.is_none();
+
+        // A closed-loop client thinks for a while after every reply, then sends its next op.
+        let closed_loop = self.workload.as_ref().is_some_and(|generator| {
+            matches!(generator.workload.arrival, Arrival::ClosedLoop { .. }) && generator.workload.clients.contains(&dst)
+        });
+        if answered && closed_loop {
+            let at = self.workload_delay();
+            self.schedule(at, WheelEvent::WorkloadThink(dst));
+        }
+    }
+
     fn fire_timer(&mut self, node: NodeKind, kind: TimerKind) {
-        // feed a timer-firing via the inbox so Replica::tick runs
-        self.inbox.get_mut(&node).unwrap().push_back(Event::TimerFired(kind));
-        self.schedule(self.now, WheelEvent::Deliver(node.clone()));
+        // feed a timer-firing as an event so Replica::tick runs
+        self.schedule(self.now, WheelEvent::Deliver { to: node, event: Event::TimerFired(kind) });
     }
 
-    fn client_think(&mut self, client_id: NodeId, op: Input) {
+    fn client_think(&mut self, client_id: NodeId, op: Operation<Input>) {
         let Some(client) = self.clients.get_mut(&client_id) else {
             return;
         };
 
+        let request_number = client.next_request();
+        match &op {
+            Operation::Apply(input) => self.history.invoke(client_id, request_number, input.clone(), self.now),
+            Operation::Register => client.registering = Some(request_number),
+            Operation::Reconfigure { .. } => {}
+        }
+
+        let id = if matches!(op, Operation::Register) { client.nonce() } else { client.client_id() };
         let request = Message::Request::<Input, Op>(ClientRequest {
-            client_id: client_id.0,
+            client_id: id,
             op,
-            request_number: 0,
+            request_number: request_number as usize,
             result: None,
         });
+        self.send_request(client_id, request_number, request);
+    }
 
-        let current_primary = client.configuration[client.current_view as usize];
-        let replica_id = NodeId(current_primary);
-        self.send(NodeKind::Client(client_id), NodeKind::Replica(replica_id), request);
+    fn client_read(&mut self, client_id: NodeId, op: Input) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+
+        let request_number = client.next_request();
+        let id = client.client_id();
+        self.history.invoke(client_id, request_number, op.clone(), self.now);
+        let request = Message::Read::<Input, Op>(ReadRequest {
+            op,
+            client_id: id,
+            request_number: request_number as usize,
+        });
+        self.send_request(client_id, request_number, request);
+    }
+
+    /// Sends a new request to the primary the client knows of, and starts its timer.
+    fn send_request(&mut self, client_id: NodeId, request_number: u64, request: Message<Input, Op>) {
+        let client = &self.clients[&client_id];
+        let (primary, timeout_ms) = (client.primary(), client.timeout_ms);
+        self.send(NodeKind::Client(client_id), NodeKind::Replica(NodeId(primary)), request.clone());
+
+        if !self.config.disable_timers {
+            self.schedule(self.now + timeout_ms, WheelEvent::ClientTimeout { client_id, request_number, request });
+        }
+    }
+
+    /// The client a reply to `client_id` goes to: the node an unregistered client id names, or else the one holding
+    /// that session, if any.
+    fn client_node(&self, client_id: u64) -> Option<NodeId> {
+        if client_id & UNREGISTERED != 0 {
+            return Some(NodeId(client_id & !UNREGISTERED));
+        }
+        self.clients.values().find(|c| c.session == Some(client_id)).map(|c| c.id)
+    }
+
+    fn client_timeout(&mut self, client_id: NodeId, request_number: u64, request: Message<Input, Op>) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+
+        let Some(replicas) = client.on_timeout(request_number) else {
+            return;
+        };
+
+        println!("resending request: {:?}, {:?} -> {:?}", request_number, client_id, replicas);
+        let timeout_ms = client.timeout_ms;
+        for replica in replicas {
+            self.send(NodeKind::Client(client_id), NodeKind::Replica(NodeId(replica)), request.clone());
+        }
+        self.schedule(self.now + timeout_ms, WheelEvent::ClientTimeout { client_id, request_number, request });
     }
 
     fn apply_effects(&mut self, from: NodeId, effs: &mut Vec<Effect<Input, Op>>) {
         for eff in effs.drain(..) {
+            if self.trace.is_some() {
+                self.record(TraceEntry::Effect { step: self.steps, at: self.now, node: from, effect: eff.clone() });
+            }
+
             match eff {
                 Effect::Send { to, message } => {
                     let from_replica = NodeKind::Replica(from);
@@ -236,8 +768,12 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
                     self.send(from_replica, to_replica, message);
                 }
                 Effect::Reply { client_id, message } => {
-                    assert!(matches!(message, Message::Reply { .. }));
-                    self.send(NodeKind::Replica(from), NodeKind::Client(NodeId(client_id)), message);
+                    assert!(matches!(message, Message::Reply { .. } | Message::Error { .. }));
+                    // A session no client holds any more was evicted, and its client registered again.
+                    match self.client_node(client_id) {
+                        Some(node) => self.send(NodeKind::Replica(from), NodeKind::Client(node), message),
+                        None => println!("dropping reply to a closed session: {:?}", client_id),
+                    }
                 }
                 Effect::Broadcast { to, message } => {
                     for replica_id in to {
@@ -251,30 +787,50 @@ impl <Input: Clone + std::fmt::Debug + 'static> Simulator<Input> {
                         self.schedule(at, WheelEvent::FireTimer { node: from, kind });
                     }
                 }
-                // Apply/commit prepared operation in backups in the moment it will be responded as PrepareOk to the primary replica.
+                Effect::CancelTimer { kind } => {
+                    println!("cancelling timer: {:?}, {:?}", from, kind);
+                    let is_cancelled = |ev: &WheelEvent<Input>| matches!(ev, WheelEvent::FireTimer { node, kind: k } if *node == from && *k == kind);
+                    for evs in self.wheel.values_mut() {
+                        evs.retain(|ev| !is_cancelled(ev));
+                    }
+                }
+                // The replica already applied the committed operation to its state machine.
                 Effect::ApplyCommited { op_number } => {
-                    let r = self.replicas.get(&from).unwrap();
-                    r.clone().commit_op(op_number);
+                    println!("applied op_number: {:?}, replica: {:?}", op_number, from);
+                }
+                // The replica left the replica group, so it stops receiving messages and timers.
+                Effect::Shutdown => {
+                    println!("shutting down replica: {:?}", from);
+                    self.replicas.remove(&from);
+                }
+                // The storage of the replica failed, so it is down like a crashed one until it restarts.
+                Effect::Failed { error } => {
+                    println!("replica failed: {:?}, {:?}", from, error);
+                    self.crash_replica(from);
                 }
-                e => todo!("{:?}", e)
             }
         }
     }
 
     fn send(&mut self, from: NodeKind, to: NodeKind, m: Message<Input, Op>) {
-        let Some(l) = self.links.0.get(&(from, to)) else {
+        let Some(l) = self.links.0.get(&(from, to)).cloned() else {
             return;
         };
 
-        if !l.up {
+        let bytes = serde_json::to_vec(&m).unwrap().len();
+        self.stats.on_send(from, to, m.name(), bytes);
+
+        if !l.up || self.rng.chance(l.drop_pct) {
+            println!("dropping message: {:?}, {:?} -> {:?}", m, from, to);
             return;
         }
 
-        let base_ms = l.base_ms;
-        // TODO: Add jitter, drop, and RNG
-        self.inbox.get_mut(&to).unwrap().push_back(Event::Msg(m.clone()));
-        let at = self.now + base_ms;
-        println!("sending message: {:?}, {:?} -> {:?}, at: {:?}", m, from, to, at);
-        self.schedule(at, WheelEvent::Deliver(to));
+        // A duplicate takes its own delay, so it may arrive before the original.
+        let copies = if self.rng.chance(l.dup_pct) { 2 } else { 1 };
+        for _ in 0..copies {
+            let at = self.now + l.base_ms + self.rng.up_to(l.jitter_ms);
+            println!("sending message: {:?}, {:?} -> {:?}, at: {:?}", m, from, to, at);
+            self.schedule(at, WheelEvent::Deliver { to, event: Event::Msg(m.clone()) });
+        }
     }
 }
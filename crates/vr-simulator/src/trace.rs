@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use vr_replica::effect::Effect;
+
+use crate::client::Op;
+use crate::simulator::{NodeId, WheelEvent};
+
+/// One line of a simulation trace, as written by `Simulator::write_trace` and read by `Simulator::replay`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceEntry<Input: Clone> {
+    /// The first line, with the seed the run needs to be replayed.
+    Start { seed: u64 },
+    /// An event scheduled from outside the run, e.g. a client request or a fault, after `step` steps.
+    Input { step: u64, at: u64, event: WheelEvent<Input> },
+    /// An event the run scheduled itself, while running step `step`.
+    Schedule { step: u64, at: u64, event: WheelEvent<Input> },
+    /// An event taken off the wheel by step `step`.
+    Fire { step: u64, at: u64, event: WheelEvent<Input> },
+    /// An effect a replica returned during step `step`.
+    Effect { step: u64, at: u64, node: NodeId, effect: Effect<Input, Op> },
+}
+
+/// Why a replay failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    /// A line of the trace could not be parsed.
+    Malformed { line: usize, error: String },
+    /// The replay went another way than the trace, from this line on. `actual` is `None` if the replay stopped early.
+    Diverged { line: usize, expected: String, actual: Option<String> },
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Malformed { line, error } => write!(f, "malformed trace at line {}: {}", line, error),
+            ReplayError::Diverged { line, expected, actual } => {
+                write!(f, "replay diverged at line {}: expected {}, got {}", line, expected, actual.as_deref().unwrap_or("nothing"))
+            }
+        }
+    }
+}
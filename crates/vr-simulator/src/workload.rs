@@ -0,0 +1,187 @@
+use crate::client::Op;
+use crate::rng::Rng;
+use crate::simulator::NodeId;
+
+/// Keeps the ops of a workload off the random stream of the network, so that the same seed makes the same ops.
+const WORKLOAD_STREAM: u64 = 0x9e6c_63d0_676a_9a99;
+
+/// How the workload picks the key of every op, out of `Workload::keys` keys.
+#[derive(Debug, Clone)]
+pub enum KeyDistribution {
+    Uniform,
+    /// Key `i` is picked with a probability proportional to `1 / (i + 1)^exponent`.
+    Zipfian { exponent: f64 },
+    /// `hot_pct` percent of the ops go to the first key, the rest spread uniformly over all of them.
+    HotKey { hot_pct: u8 },
+}
+
+/// When the clients of a workload send their ops.
+#[derive(Debug, Clone)]
+pub enum Arrival {
+    /// Every client sends its next op after a reply to the previous one, `think_ms` up to twice that later.
+    ClosedLoop { think_ms: u64 },
+    /// An op arrives every `interval_ms` on average, whether or not the earlier ones were answered. It goes to a
+    /// client with no request pending, and is lost if there is none.
+    OpenLoop { interval_ms: u64 },
+}
+
+/// Drives many clients with random ops over `client::Op`, from the simulator seed.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    /// The clients sending the ops, which must be added to the simulator.
+    pub clients: Vec<NodeId>,
+    /// Ops go to the keys `k0` up to `k{keys - 1}`.
+    pub keys: u64,
+    pub distribution: KeyDistribution,
+    /// The share of gets and deletes, in percent. The rest are sets.
+    pub get_pct: u8,
+    pub del_pct: u8,
+    /// Sends the gets as reads, which skip the log.
+    pub reads: bool,
+    pub arrival: Arrival,
+    /// No op starts after this time.
+    pub until: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            clients: Vec::new(),
+            keys: 10,
+            distribution: KeyDistribution::Uniform,
+            get_pct: 50,
+            del_pct: 10,
+            reads: false,
+            arrival: Arrival::ClosedLoop { think_ms: 100 },
+            until: 10_000,
+        }
+    }
+}
+
+/// The op a workload sends next, and whether it goes as a read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkloadOp {
+    pub op: Op,
+    pub read: bool,
+}
+
+/// Makes the ops and delays of a `Workload`.
+#[derive(Debug)]
+pub(crate) struct Generator {
+    pub(crate) workload: Workload,
+    rng: Rng,
+    /// The cumulative probability of every key, for `KeyDistribution::Zipfian`.
+    cdf: Vec<f64>,
+    /// How many ops were made, which gives every set its own value.
+    made: u64,
+}
+
+impl Generator {
+    pub(crate) fn new(workload: Workload, seed: u64) -> Self {
+        let cdf = match workload.distribution {
+            KeyDistribution::Zipfian { exponent } => {
+                let weights = (0..workload.keys).map(|i| 1.0 / ((i + 1) as f64).powf(exponent)).collect::<Vec<_>>();
+                let total = weights.iter().sum::<f64>();
+                weights
+                    .iter()
+                    .scan(0.0, |sum, w| {
+                        *sum += w / total;
+                        Some(*sum)
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Self { workload, rng: Rng::new(seed ^ WORKLOAD_STREAM), cdf, made: 0 }
+    }
+
+    pub(crate) fn next_op(&mut self) -> WorkloadOp {
+        self.made += 1;
+        let key = format!("k{}", self.next_key());
+        let roll = self.rng.up_to(99) as u8;
+        if roll < self.workload.get_pct {
+            WorkloadOp { op: Op::Get(key, None), read: self.workload.reads }
+        } else if roll < self.workload.get_pct.saturating_add(self.workload.del_pct) {
+            WorkloadOp { op: Op::Del(key), read: false }
+        } else {
+            WorkloadOp { op: Op::Set(key, self.made), read: false }
+        }
+    }
+
+    /// How long until the next op: after a reply in a closed loop, or after the last arrival in an open one.
+    pub(crate) fn next_delay(&mut self) -> u64 {
+        match self.workload.arrival {
+            Arrival::ClosedLoop { think_ms } => think_ms + self.rng.up_to(think_ms),
+            Arrival::OpenLoop { interval_ms } => 1 + self.rng.up_to(2 * interval_ms.max(1) - 2),
+        }
+    }
+
+    /// Picks one of `clients`.
+    pub(crate) fn pick(&mut self, clients: &[NodeId]) -> Option<NodeId> {
+        if clients.is_empty() {
+            return None;
+        }
+        Some(clients[self.rng.up_to(clients.len() as u64 - 1) as usize])
+    }
+
+    fn next_key(&mut self) -> u64 {
+        let last = self.workload.keys.max(1) - 1;
+        match self.workload.distribution {
+            KeyDistribution::Uniform => self.rng.up_to(last),
+            KeyDistribution::Zipfian { .. } => {
+                let roll = self.rng.next_u64() as f64 / u64::MAX as f64;
+                (self.cdf.partition_point(|p| *p < roll) as u64).min(last)
+            }
+            KeyDistribution::HotKey { hot_pct } => {
+                if self.rng.chance(hot_pct) {
+                    0
+                } else {
+                    self.rng.up_to(last)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_counts(distribution: KeyDistribution) -> Vec<u64> {
+        let workload = Workload { keys: 10, distribution, ..Default::default() };
+        let mut generator = Generator::new(workload, 1);
+        let mut counts = vec![0; 10];
+        for _ in 0..10_000 {
+            counts[generator.next_key() as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_key_distributions() {
+        let uniform = key_counts(KeyDistribution::Uniform);
+        assert!(uniform.iter().all(|c| (800..1200).contains(c)), "{:?}", uniform);
+
+        let zipfian = key_counts(KeyDistribution::Zipfian { exponent: 1.0 });
+        assert!(zipfian.windows(2).all(|w| w[0] > w[1]), "{:?}", zipfian);
+        assert!((3000..3800).contains(&zipfian[0]), "{:?}", zipfian);
+
+        let hot = key_counts(KeyDistribution::HotKey { hot_pct: 90 });
+        assert!((9000..9300).contains(&hot[0]), "{:?}", hot);
+    }
+
+    #[test]
+    fn test_op_mix() {
+        let workload = Workload { get_pct: 30, del_pct: 20, reads: true, ..Default::default() };
+        let mut generator = Generator::new(workload, 1);
+        let ops = (0..10_000).map(|_| generator.next_op()).collect::<Vec<_>>();
+
+        let gets = ops.iter().filter(|o| matches!(o.op, Op::Get(..)) && o.read).count();
+        let dels = ops.iter().filter(|o| matches!(o.op, Op::Del(..))).count();
+        let sets = ops.iter().filter(|o| matches!(o.op, Op::Set(..))).count();
+        assert!((2700..3300).contains(&gets), "{}", gets);
+        assert!((1700..2300).contains(&dels), "{}", dels);
+        assert!((4700..5300).contains(&sets), "{}", sets);
+    }
+}